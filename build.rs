@@ -0,0 +1,110 @@
+//! Downloads the libraries embedded by `marky --self-contained` into `$OUT_DIR/lib`.
+//!
+//! Versions must match the CDN links in templates/template.hbs. Without network the build still
+//! succeeds, `--self-contained` then fails for the missing libraries.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const HIGHLIGHT_VERSION: &str = "11.7.0";
+const KATEX_VERSION: &str = "0.16.4";
+const MERMAID_VERSION: &str = "9.3.0";
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("cargo sets OUT_DIR")).join("lib");
+    fs::create_dir_all(&out_dir).expect("can't create the library directory");
+
+    let highlight =
+        format!("https://cdnjs.cloudflare.com/ajax/libs/highlight.js/{HIGHLIGHT_VERSION}");
+    let katex = format!("https://cdn.jsdelivr.net/npm/katex@{KATEX_VERSION}/dist");
+    let mermaid = format!("https://cdn.jsdelivr.net/npm/mermaid@{MERMAID_VERSION}/dist");
+
+    let mut complete = [
+        (
+            "highlight/highlight.min.js",
+            format!("{highlight}/highlight.min.js"),
+        ),
+        (
+            "highlight/default.min.css",
+            format!("{highlight}/styles/default.min.css"),
+        ),
+        ("katex/katex.min.js", format!("{katex}/katex.min.js")),
+        ("katex/katex.min.css", format!("{katex}/katex.min.css")),
+        (
+            "mermaid/mermaid.min.js",
+            format!("{mermaid}/mermaid.min.js"),
+        ),
+    ]
+    .iter()
+    .fold(true, |complete, (path, url)| {
+        fetch(&out_dir.join(path), url) && complete
+    });
+
+    // Only woff2 fonts are embedded, every browser that runs KaTeX supports them
+    match fs::read_to_string(out_dir.join("katex/katex.min.css")) {
+        Ok(css) => {
+            for font in woff2_fonts(&css) {
+                complete = fetch(
+                    &out_dir.join("katex").join(&font),
+                    &format!("{katex}/{font}"),
+                ) && complete;
+            }
+        }
+        Err(_) => complete = false,
+    }
+
+    // Without any rerun-if directive cargo runs this again on the next change, retrying the
+    // downloads that failed
+    if complete {
+        println!("cargo:rerun-if-changed=build.rs");
+    }
+}
+
+/// Downloads `url` to `path` unless it exists already, returning whether `path` exists.
+fn fetch(path: &Path, url: &str) -> bool {
+    if path.exists() {
+        return true;
+    }
+
+    let download = || -> Result<(), Box<dyn std::error::Error>> {
+        let data = reqwest::blocking::get(url)?.error_for_status()?.bytes()?;
+
+        fs::create_dir_all(path.parent().expect("library files are in a directory"))?;
+        fs::write(path, data)?;
+
+        Ok(())
+    };
+
+    match download() {
+        Ok(()) => true,
+        Err(e) => {
+            println!(
+                "cargo:warning=can't download {}, --self-contained won't embed it: {}",
+                url, e
+            );
+            false
+        }
+    }
+}
+
+/// Paths of the woff2 fonts a stylesheet references, like `fonts/KaTeX_Main-Regular.woff2`.
+fn woff2_fonts(css: &str) -> Vec<String> {
+    let mut fonts: Vec<String> = css
+        .match_indices("fonts/")
+        .filter_map(|(start, prefix)| {
+            let rest = &css[start + prefix.len()..];
+            let stem: String = rest
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+                .collect();
+
+            (!stem.is_empty() && rest[stem.len()..].starts_with(".woff2"))
+                .then(|| format!("fonts/{}.woff2", stem))
+        })
+        .collect();
+
+    fonts.sort();
+    fonts.dedup();
+    fonts
+}
@@ -0,0 +1,211 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use lol_html::{element, html_content::ContentType, text, HtmlRewriter, Settings};
+use markdown::mdast::Node;
+
+use crate::html;
+
+/// GitHub-style `> [!NOTE]` callout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlertKind {
+    Note,
+    Tip,
+    Important,
+    Warning,
+    Caution,
+}
+
+impl AlertKind {
+    /// Parses a `[!KIND]` marker, case-insensitive like GitHub.
+    fn parse(marker: &str) -> Option<Self> {
+        let kind = marker.strip_prefix("[!")?.strip_suffix(']')?;
+
+        match kind.to_ascii_uppercase().as_str() {
+            "NOTE" => Some(AlertKind::Note),
+            "TIP" => Some(AlertKind::Tip),
+            "IMPORTANT" => Some(AlertKind::Important),
+            "WARNING" => Some(AlertKind::Warning),
+            "CAUTION" => Some(AlertKind::Caution),
+            _ => None,
+        }
+    }
+
+    fn class(&self) -> &'static str {
+        match self {
+            AlertKind::Note => "markdown-alert-note",
+            AlertKind::Tip => "markdown-alert-tip",
+            AlertKind::Important => "markdown-alert-important",
+            AlertKind::Warning => "markdown-alert-warning",
+            AlertKind::Caution => "markdown-alert-caution",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            AlertKind::Note => "Note",
+            AlertKind::Tip => "Tip",
+            AlertKind::Important => "Important",
+            AlertKind::Warning => "Warning",
+            AlertKind::Caution => "Caution",
+        }
+    }
+
+    fn icon(&self) -> &'static str {
+        match self {
+            AlertKind::Note => "ℹ️",
+            AlertKind::Tip => "💡",
+            AlertKind::Important => "📢",
+            AlertKind::Warning => "⚠️",
+            AlertKind::Caution => "🛑",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Alert {
+    kind: AlertKind,
+    /// The first paragraph holds nothing but the marker.
+    marker_only: bool,
+}
+
+/// Detects alerts among the blockquotes of the document in rendered order, raw HTML blockquotes
+/// included, so they pair with the rendered elements by position.
+pub fn alerts(root: &Node) -> Vec<Option<Alert>> {
+    html::rendered_order(
+        root,
+        |node| matches!(node, Node::BlockQuote(_)),
+        "blockquote",
+    )
+    .into_iter()
+    .map(|node| match node {
+        Some(Node::BlockQuote(blockquote)) => detect(blockquote.children.first()),
+        _ => None,
+    })
+    .collect()
+}
+
+fn detect(first_child: Option<&Node>) -> Option<Alert> {
+    let paragraph = match first_child {
+        Some(paragraph @ Node::Paragraph(_)) => paragraph.to_string(),
+        _ => return None,
+    };
+
+    // The marker must be alone on the first line
+    let first_line = paragraph.lines().next()?.trim();
+    let kind = AlertKind::parse(first_line)?;
+
+    Some(Alert {
+        kind,
+        marker_only: paragraph.trim() == first_line,
+    })
+}
+
+struct Blockquote {
+    alert: Option<Alert>,
+    marker_pending: bool,
+    /// Text of the first paragraph held back until the marker is complete.
+    pending_text: String,
+}
+
+/// Turns blockquotes detected as alerts into callout markup compatible with GitHub.
+pub fn render_alerts(html_page: &str, alerts: &[Option<Alert>]) -> anyhow::Result<String> {
+    let mut output = vec![];
+    let mut alerts = alerts.iter();
+
+    let blockquotes: Rc<RefCell<Vec<Blockquote>>> = Rc::new(RefCell::new(Vec::new()));
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                element!("blockquote", |el| {
+                    let alert = alerts.next().copied().flatten();
+
+                    blockquotes.borrow_mut().push(Blockquote {
+                        alert,
+                        marker_pending: alert.is_some(),
+                        pending_text: String::new(),
+                    });
+
+                    let blockquotes = blockquotes.clone();
+                    el.on_end_tag(move |_| {
+                        blockquotes.borrow_mut().pop();
+                        Ok(())
+                    })?;
+
+                    if let Some(alert) = alert {
+                        el.set_tag_name("div")?;
+                        el.set_attribute(
+                            "class",
+                            &format!("markdown-alert {}", alert.kind.class()),
+                        )?;
+                        el.prepend(
+                            &format!(
+                                "\n<p class=\"markdown-alert-title\"><span class=\"markdown-alert-icon\" aria-hidden=\"true\">{}</span>{}</p>",
+                                alert.kind.icon(),
+                                alert.kind.title()
+                            ),
+                            ContentType::Html,
+                        );
+                    }
+
+                    Ok(())
+                }),
+                element!("blockquote > p:first-child", |el| {
+                    let mut blockquotes = blockquotes.borrow_mut();
+
+                    if let Some(Blockquote {
+                        alert: Some(alert),
+                        marker_pending,
+                        ..
+                    }) = blockquotes.last_mut()
+                    {
+                        if alert.marker_only {
+                            *marker_pending = false;
+                            el.remove();
+                        }
+                    }
+
+                    Ok(())
+                }),
+                text!("blockquote > p:first-child", |chunk| {
+                    let mut blockquotes = blockquotes.borrow_mut();
+
+                    let blockquote = match blockquotes.last_mut() {
+                        Some(blockquote) if blockquote.marker_pending => blockquote,
+                        _ => return Ok(()),
+                    };
+
+                    // The marker may be split across chunks, they are emitted once it ends
+                    blockquote.pending_text.push_str(chunk.as_str());
+                    let text = blockquote.pending_text.trim_start();
+
+                    let rest = match text.find(']') {
+                        Some(end) if AlertKind::parse(&text[..=end]).is_some() => {
+                            text[end + 1..].trim_start_matches(['\r', '\n']).to_string()
+                        }
+                        Some(_) => text.to_string(),
+                        None if chunk.last_in_text_node() => text.to_string(),
+                        None => {
+                            chunk.remove();
+                            return Ok(());
+                        }
+                    };
+
+                    blockquote.marker_pending = false;
+                    blockquote.pending_text.clear();
+                    chunk.replace(&rest, ContentType::Html);
+
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
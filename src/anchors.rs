@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use lol_html::{element, html_content::ContentType, HtmlRewriter, Settings};
+use markdown::mdast::Node;
+
+use crate::html;
+use crate::toc::Heading;
+
+const HEADINGS: &str = "h1, h2, h3, h4, h5, h6";
+
+/// Assigns GitHub-compatible `id` attributes to the rendered headings.
+///
+/// Rendered headings are paired by position with the headings and raw HTML of the mdast,
+/// so headings written as raw HTML or with an explicit `id` are left as is.
+pub fn anchor_headings(
+    html: &str,
+    root: &Node,
+    headings: &[Heading],
+    permalinks: bool,
+) -> anyhow::Result<String> {
+    let by_offset: HashMap<usize, &Heading> = headings
+        .iter()
+        .filter_map(|heading| Some((heading.offset?, heading)))
+        .collect();
+
+    let mut order = html::rendered_order(root, |node| matches!(node, Node::Heading(_)), HEADINGS)
+        .into_iter()
+        .map(|node| by_offset.get(&node?.position()?.start.offset).copied());
+
+    let mut output = vec![];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!(HEADINGS, |el| {
+                let heading = match order.next().flatten() {
+                    Some(heading) if !el.has_attribute("id") => heading,
+                    _ => return Ok(()),
+                };
+
+                if el.tag_name() != format!("h{}", heading.depth) {
+                    return Ok(());
+                }
+
+                el.set_attribute("id", &heading.slug)?;
+
+                if permalinks {
+                    el.prepend(
+                        &format!(
+                            "<a class=\"anchor\" href=\"#{}\" aria-hidden=\"true\">#</a>",
+                            heading.slug
+                        ),
+                        ContentType::Html,
+                    );
+                }
+
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
@@ -0,0 +1,563 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use colored::Colorize;
+use lol_html::{element, HtmlRewriter, Settings};
+use reqwest::Url;
+
+use crate::document::{IncludeLevel, RenderOptions};
+use crate::images::{self, DisplaySize};
+use crate::{cache, fetch, info, ioutil, pool, sanitize, urls, warn};
+
+/// Files fetched and converted at once, downloads mostly wait on the network.
+const CONCURRENT_FETCHES: usize = 8;
+
+/// How a referenced file is embedded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    /// Converted and resized like the rest of the images
+    Image,
+    /// Video, audio and anything else, embedded byte for byte
+    Media,
+    Icon,
+    /// Its own `url(...)` references are embedded too
+    Stylesheet,
+}
+
+impl Kind {
+    /// Expected content type of downloads.
+    fn media(&self) -> &'static str {
+        match self {
+            Kind::Image | Kind::Icon => "image/",
+            Kind::Media => "",
+            Kind::Stylesheet => "text/css",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Reference {
+    /// URL as written in the page, the key to substitute it by.
+    url: String,
+    kind: Kind,
+    size: DisplaySize,
+}
+
+impl Reference {
+    /// Location of the reference, with the character references of the attribute decoded.
+    fn location(&self, base_dir: &Path) -> Option<Location> {
+        Location::of(&sanitize::decode_entities(&self.url), base_dir)
+    }
+}
+
+/// Types of embeddable files that aren't images, by extension.
+const MEDIA_TYPES: [(&str, &str); 18] = [
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("css", "text/css"),
+    ("vtt", "text/vtt"),
+    ("woff2", "font/woff2"),
+    ("woff", "font/woff"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+];
+
+/// Nesting limit of stylesheets importing stylesheets.
+const MAX_STYLESHEET_DEPTH: usize = 4;
+
+fn media_type(data: &[u8], name: &str) -> &'static str {
+    if let Some(mime) = images::mime_type(data, name) {
+        return mime;
+    }
+
+    let (path, _) = urls::split_suffix(name);
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+
+    MEDIA_TYPES
+        .iter()
+        .find(|(name, _)| Some(*name) == extension.as_deref())
+        .map(|(_, mime)| *mime)
+        .unwrap_or("application/octet-stream")
+}
+
+/// Where a referenced file lives.
+enum Location {
+    Local(PathBuf),
+    Remote(Url),
+}
+
+impl Location {
+    /// Location of a reference in the document, `None` for anchors, data URIs and the like.
+    fn of(url: &str, base_dir: &Path) -> Option<Self> {
+        let url = url.trim();
+
+        if let Some(remote) = Self::remote(url) {
+            return Some(remote);
+        }
+
+        urls::is_relative_local(url).then(|| Location::Local(urls::resolve(base_dir, url)))
+    }
+
+    fn remote(url: &str) -> Option<Self> {
+        let url = match url.strip_prefix("//") {
+            Some(rest) => format!("https://{}", rest),
+            None => url.to_string(),
+        };
+
+        let parsed = Url::parse(&url).ok()?;
+        matches!(parsed.scheme(), "http" | "https").then_some(Location::Remote(parsed))
+    }
+
+    /// Location of a reference inside this file, like a font in a stylesheet.
+    fn join(&self, url: &str) -> Option<Self> {
+        let url = url.trim();
+
+        if let Some(remote) = Self::remote(url) {
+            return Some(remote);
+        }
+
+        match self {
+            Location::Local(path) => Self::of(url, &urls::parent_dir(path)),
+            Location::Remote(base) => {
+                if url.is_empty() || url.starts_with('#') || url.starts_with("data:") {
+                    return None;
+                }
+
+                base.join(url).ok().map(Location::Remote)
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Location::Local(path) => path.display().to_string(),
+            Location::Remote(url) => url.to_string(),
+        }
+    }
+
+    /// Reads the file, `None` when the include level skips it.
+    fn load(
+        &self,
+        kind: Kind,
+        options: &RenderOptions,
+        confine: Option<&Path>,
+    ) -> Result<Option<Vec<u8>>> {
+        let level = options.include_images.expect("must be not none");
+
+        match self {
+            Location::Remote(url) if level != IncludeLevel::Local => {
+                info!("Downloading {}", url);
+                Ok(Some(fetch::fetch(
+                    url.as_str(),
+                    kind.media(),
+                    &options.fetch,
+                )?))
+            }
+            Location::Local(path) if level != IncludeLevel::Remote => {
+                info!("Reading {}", path.display());
+                Ok(Some(ioutil::read_referenced(path, confine)?))
+            }
+            _ => {
+                info!("Skipping {}", self.name());
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Attributes referencing files, selector, attribute, kind and whether it is a `srcset`.
+const ATTRIBUTES: [(&str, &str, Kind, bool); 10] = [
+    ("img[src]", "src", Kind::Image, false),
+    ("img[srcset]", "srcset", Kind::Image, true),
+    ("picture > source[srcset]", "srcset", Kind::Image, true),
+    ("video[src]", "src", Kind::Media, false),
+    ("video[poster]", "poster", Kind::Image, false),
+    ("audio[src]", "src", Kind::Media, false),
+    ("source[src]", "src", Kind::Media, false),
+    ("track[src]", "src", Kind::Media, false),
+    ("link[rel~=icon][href]", "href", Kind::Icon, false),
+    (
+        "link[rel~=stylesheet][href]",
+        "href",
+        Kind::Stylesheet,
+        false,
+    ),
+];
+
+/// Replaces references to images, media, icons and stylesheets with data URIs.
+///
+/// Every file is fetched and converted concurrently, files that can't be loaded keep their
+/// original reference. Local files outside of `confine` are never read.
+pub fn embed(
+    html_page: &str,
+    base_dir: &Path,
+    options: &RenderOptions,
+    confine: Option<&Path>,
+) -> Result<String> {
+    let references = collect(html_page)?;
+
+    let embedded = pool::map(&references, CONCURRENT_FETCHES, |reference| {
+        embed_reference(reference, base_dir, options, confine)
+    });
+
+    let mut data_uris = HashMap::new();
+    for (reference, data_uri) in references.iter().zip(embedded) {
+        match data_uri {
+            Ok(Some(data_uri)) => {
+                data_uris.insert(reference.clone(), data_uri);
+            }
+            Ok(None) => (),
+            Err(e) => warn!("can't include {}: {}", reference.url.cyan(), e),
+        }
+    }
+
+    substitute(html_page, &data_uris)
+}
+
+/// Where the files referenced by a document end up.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetMode {
+    /// Keep referencing the original files
+    #[default]
+    Link,
+    /// Copy them into `assets/` next to the output, with content-hashed names
+    Copy,
+}
+
+/// Directory next to the output that assets are copied to.
+const ASSETS_DIR: &str = "assets";
+
+/// Copies local images, media and icons into the assets directory of `out_dir`.
+///
+/// The references in the page must be relative to `out_dir` already. Files outside of `confine`
+/// are never read.
+pub fn copy(html_page: &str, out_dir: &Path, confine: Option<&Path>) -> Result<String> {
+    let references: Vec<Reference> = collect(html_page)?
+        .into_iter()
+        .filter(|reference| reference.kind != Kind::Stylesheet)
+        .collect();
+
+    let mut copied = HashMap::new();
+    for reference in references {
+        let Some(Location::Local(path)) = reference.location(out_dir) else {
+            continue;
+        };
+
+        // The output directory may not exist yet
+        let path = urls::normalize(&path);
+
+        match copy_file(&path, out_dir, confine) {
+            Ok(url) => {
+                let (_, suffix) = urls::split_suffix(&reference.url);
+                copied.insert(reference.clone(), format!("{}{}", url, suffix));
+            }
+            Err(e) => warn!("can't copy {}: {}", path.display().to_string().cyan(), e),
+        }
+    }
+
+    substitute(html_page, &copied)
+}
+
+/// Copies a file into the assets directory, returning its URL relative to `out_dir`.
+fn copy_file(path: &Path, out_dir: &Path, confine: Option<&Path>) -> Result<String> {
+    let data = ioutil::read_referenced(path, confine)?;
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+
+    let url = write_asset(&data, &stem, extension.as_deref(), out_dir)?;
+    info!("Copied {} to {}", path.display(), url);
+
+    Ok(url)
+}
+
+/// Writes `assets/<stem>-<hash>.<extension>`, returning its URL relative to `out_dir`.
+///
+/// Files with the same content are written once.
+pub fn write_asset(
+    data: &[u8],
+    stem: &str,
+    extension: Option<&str>,
+    out_dir: &Path,
+) -> Result<String> {
+    let hash = cache::hash(data);
+
+    let stem: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+
+    let name = match extension {
+        Some(extension) => format!("{}-{}.{}", stem, &hash[..16], extension),
+        None => format!("{}-{}", stem, &hash[..16]),
+    };
+
+    let dir = out_dir.join(ASSETS_DIR);
+    let target = dir.join(&name);
+
+    if !target.exists() {
+        fs::create_dir_all(&dir)?;
+        fs::write(&target, data)?;
+    }
+
+    Ok(format!("{}/{}", ASSETS_DIR, name))
+}
+
+fn display_size(el: &lol_html::html_content::Element, kind: Kind, srcset: bool) -> DisplaySize {
+    // Only the main image is displayed at the size of its element
+    if kind == Kind::Image && !srcset && el.tag_name() == "img" {
+        DisplaySize::parse(el.get_attribute("width"), el.get_attribute("height"))
+    } else {
+        DisplaySize::default()
+    }
+}
+
+fn collect(html_page: &str) -> Result<Vec<Reference>> {
+    let references: RefCell<Vec<Reference>> = RefCell::new(Vec::new());
+
+    let handlers = ATTRIBUTES
+        .iter()
+        .map(|&(selector, attribute, kind, srcset)| {
+            let references = &references;
+
+            element!(selector, move |el| {
+                let value = el.get_attribute(attribute).expect("attribute was required");
+                let size = display_size(el, kind, srcset);
+
+                let urls = if srcset {
+                    urls::srcset_candidates(&value)
+                        .into_iter()
+                        .map(|(url, _)| url.to_string())
+                        .collect()
+                } else {
+                    vec![value.trim().to_string()]
+                };
+
+                let mut references = references.borrow_mut();
+                for url in urls {
+                    let reference = Reference { url, kind, size };
+
+                    if !references.contains(&reference) {
+                        references.push(reference);
+                    }
+                }
+
+                Ok(())
+            })
+        })
+        .collect();
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: handlers,
+            ..Settings::default()
+        },
+        |_: &[u8]| (),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(references.into_inner())
+}
+
+fn substitute(html_page: &str, data_uris: &HashMap<Reference, String>) -> Result<String> {
+    let mut output = vec![];
+
+    let handlers = ATTRIBUTES
+        .iter()
+        .map(|&(selector, attribute, kind, srcset)| {
+            element!(selector, move |el| {
+                let value = el.get_attribute(attribute).expect("attribute was required");
+                let size = display_size(el, kind, srcset);
+
+                let data_uri = |url: &str| {
+                    data_uris.get(&Reference {
+                        url: url.to_string(),
+                        kind,
+                        size,
+                    })
+                };
+
+                if srcset {
+                    let candidates: Vec<String> = urls::srcset_candidates(&value)
+                        .into_iter()
+                        .map(|(url, descriptor)| {
+                            let url = data_uri(url).map(String::as_str).unwrap_or(url);
+                            format!("{} {}", url, descriptor).trim_end().to_string()
+                        })
+                        .collect();
+
+                    el.set_attribute(attribute, &candidates.join(", "))?;
+                } else if let Some(data_uri) = data_uri(value.trim()) {
+                    el.set_attribute(attribute, data_uri)?;
+                }
+
+                Ok(())
+            })
+        })
+        .collect();
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: handlers,
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
+
+fn embed_reference(
+    reference: &Reference,
+    base_dir: &Path,
+    options: &RenderOptions,
+    confine: Option<&Path>,
+) -> Result<Option<String>> {
+    let Some(location) = reference.location(base_dir) else {
+        return Ok(None);
+    };
+
+    let Some(data) = location.load(reference.kind, options, confine)? else {
+        return Ok(None);
+    };
+
+    let data_uri = match reference.kind {
+        Kind::Image => images::prepare(
+            data,
+            &reference.url,
+            reference.size,
+            &options.image_options(),
+        )?
+        .data_uri(),
+        Kind::Stylesheet => {
+            let css = String::from_utf8_lossy(&data);
+            stylesheet_data_uri(&css, &location, options, confine, 0)
+        }
+        Kind::Media | Kind::Icon => data_uri(media_type(&data, &reference.url), &data),
+    };
+
+    Ok(Some(data_uri))
+}
+
+fn data_uri(mime: &str, data: &[u8]) -> String {
+    format!("data:{};base64,{}", mime, base64::encode(data))
+}
+
+fn stylesheet_data_uri(
+    css: &str,
+    location: &Location,
+    options: &RenderOptions,
+    confine: Option<&Path>,
+    depth: usize,
+) -> String {
+    data_uri(
+        "text/css",
+        inline_urls(css, location, options, confine, depth).as_bytes(),
+    )
+}
+
+/// Replaces `url(...)` references of a stylesheet, such as fonts and backgrounds, with data URIs.
+fn inline_urls(
+    css: &str,
+    location: &Location,
+    options: &RenderOptions,
+    confine: Option<&Path>,
+    depth: usize,
+) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+
+    while let Some(start) = rest.find("url(") {
+        let (before, after) = rest.split_at(start + "url(".len());
+        result.push_str(before);
+
+        let end = match after.find(')') {
+            Some(end) => end,
+            None => {
+                rest = after;
+                break;
+            }
+        };
+
+        let url = after[..end].trim().trim_matches(|c| c == '"' || c == '\'');
+
+        match inline_url(url, location, options, confine, depth) {
+            Some(data_uri) => result.push_str(&format!("\"{}\"", data_uri)),
+            None => result.push_str(&after[..end]),
+        }
+
+        rest = &after[end..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+fn inline_url(
+    url: &str,
+    location: &Location,
+    options: &RenderOptions,
+    confine: Option<&Path>,
+    depth: usize,
+) -> Option<String> {
+    let nested = location.join(url)?;
+    let is_stylesheet = url.split(['?', '#']).next()?.ends_with(".css");
+
+    let kind = if is_stylesheet {
+        Kind::Stylesheet
+    } else {
+        Kind::Media
+    };
+
+    let data = match nested.load(kind, options, confine) {
+        Ok(data) => data?,
+        Err(e) => {
+            warn!("can't include {}: {}", nested.name().cyan(), e);
+            return None;
+        }
+    };
+
+    if is_stylesheet && depth < MAX_STYLESHEET_DEPTH {
+        let css = String::from_utf8_lossy(&data);
+        return Some(stylesheet_data_uri(
+            &css,
+            &nested,
+            options,
+            confine,
+            depth + 1,
+        ));
+    }
+
+    Some(data_uri(media_type(&data, url), &data))
+}
@@ -0,0 +1,133 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use anyhow::Result;
+use lol_html::{element, html_content::ContentType, HtmlRewriter, Settings};
+use serde::Serialize;
+
+use crate::cache;
+
+/// Marks where a top-level element starts, never part of a rendered document.
+const BOUNDARY: char = '\u{1}';
+
+/// Source line attribute of `lines::annotate`.
+const SOURCE_LINE: &str = "data-source-line";
+
+/// Top-level block of a rendered document, keyed by a hash of its markup.
+///
+/// The live preview only replaces the blocks whose key changed.
+#[derive(Serialize, Clone, Debug)]
+pub struct Block {
+    pub key: String,
+    pub html: String,
+    /// Source lines of the annotated elements in document order. They are left out of the key,
+    /// a line added above only moves them and the preview updates the kept block.
+    pub lines: Vec<usize>,
+}
+
+/// Splits the body into top-level elements, text between them belongs to the preceding one.
+///
+/// Raw HTML with implied end tags nests the rest of the document into one block.
+pub fn split(html: &str) -> Result<Vec<Block>> {
+    let html = html.replace(BOUNDARY, "");
+    let depth = Rc::new(Cell::new(0usize));
+    let mut output = vec![];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!("*", |el| {
+                if depth.get() == 0 {
+                    el.before(&BOUNDARY.to_string(), ContentType::Html);
+                }
+
+                if el.can_have_content() {
+                    depth.set(depth.get() + 1);
+
+                    let depth = depth.clone();
+                    el.on_end_tag(move |_| {
+                        depth.set(depth.get().saturating_sub(1));
+                        Ok(())
+                    })?;
+                }
+
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    let output = String::from_utf8(output)?;
+
+    output
+        .split(BOUNDARY)
+        .enumerate()
+        .filter(|(i, html)| *i > 0 || !html.trim().is_empty())
+        .map(|(_, html)| {
+            let (unannotated, lines) = source_lines(html)?;
+
+            Ok(Block {
+                key: cache::hash(unannotated.as_bytes())[..16].to_string(),
+                html: html.to_string(),
+                lines,
+            })
+        })
+        .collect()
+}
+
+/// Removes the source lines of a block, returning the markup without them and the lines.
+fn source_lines(html: &str) -> Result<(String, Vec<usize>)> {
+    let lines = RefCell::new(Vec::new());
+    let mut output = vec![];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!(format!("[{}]", SOURCE_LINE), |el| {
+                let line = el
+                    .get_attribute(SOURCE_LINE)
+                    .expect("attribute was required");
+                if let Ok(line) = line.parse() {
+                    lines.borrow_mut().push(line);
+                }
+
+                el.remove_attribute(SOURCE_LINE);
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    Ok((String::from_utf8(output)?, lines.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moved_lines_keep_keys() {
+        let before =
+            split(r#"<h1 data-source-line="1">A</h1><p data-source-line="3">B</p>"#).unwrap();
+        let after =
+            split(r#"<h1 data-source-line="2">A</h1><p data-source-line="4">B</p>"#).unwrap();
+
+        assert_eq!(before[0].key, after[0].key);
+        assert_eq!(before[1].key, after[1].key);
+        assert_eq!(after[1].lines, [4]);
+    }
+
+    #[test]
+    fn changed_markup_changes_key() {
+        let before = split(r#"<p data-source-line="1">A</p>"#).unwrap();
+        let after = split(r#"<p data-source-line="1">B</p>"#).unwrap();
+
+        assert_ne!(before[0].key, after[0].key);
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{paths, warn};
+
+/// Response of a remote resource, the body is stored as a blob.
+#[derive(Serialize, Deserialize)]
+struct Fetched {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Seconds since the Unix epoch, entries written before it was stored are stale.
+    #[serde(default)]
+    fetched_at: u64,
+    blob: String,
+}
+
+/// `ETag` and `Last-Modified` of a response.
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Whether the response can't be revalidated, only its age tells if it's still fresh.
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Cached response of a URL.
+pub struct Cached {
+    pub validators: Validators,
+    /// Time since it was downloaded.
+    pub age: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Output of processing a blob, like optimizing an image.
+#[derive(Serialize, Deserialize)]
+pub struct Processed {
+    pub mime: String,
+    blob: String,
+}
+
+pub fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn blob_path(hash: &str) -> PathBuf {
+    paths::dirs::cache().join("blobs").join(hash)
+}
+
+fn entry_path(kind: &str, key: &str) -> PathBuf {
+    paths::dirs::cache()
+        .join(kind)
+        .join(format!("{}.json", hash(key.as_bytes())))
+}
+
+fn read_blob(hash: &str) -> Option<Vec<u8>> {
+    let data = fs::read(blob_path(hash)).ok()?;
+
+    // A truncated or edited blob is a miss
+    (self::hash(&data) == hash).then_some(data)
+}
+
+fn write_blob(data: &[u8]) -> Result<String> {
+    let hash = hash(data);
+    let path = blob_path(&hash);
+
+    if !path.exists() {
+        fs::create_dir_all(path.parent().expect("blobs have a directory"))?;
+        fs::write(path, data)?;
+    }
+
+    Ok(hash)
+}
+
+fn read_entry<T: for<'de> Deserialize<'de>>(kind: &str, key: &str) -> Option<T> {
+    let json = fs::read(entry_path(kind, key)).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+fn write_entry<T: Serialize>(kind: &str, key: &str, entry: &T) -> Result<()> {
+    let path = entry_path(kind, key);
+
+    fs::create_dir_all(path.parent().expect("entries have a directory"))?;
+    fs::write(path, serde_json::to_vec(entry)?)?;
+
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// Cached response of a URL, with the validators to revalidate it.
+pub fn fetched(url: &str) -> Option<Cached> {
+    let entry = read_entry::<Fetched>("fetched", url)?;
+    let data = read_blob(&entry.blob)?;
+
+    Some(Cached {
+        validators: Validators {
+            etag: entry.etag,
+            last_modified: entry.last_modified,
+        },
+        age: Duration::from_secs(now().saturating_sub(entry.fetched_at)),
+        data,
+    })
+}
+
+pub fn store_fetched(url: &str, validators: Validators, data: &[u8]) {
+    let stored = write_blob(data).and_then(|blob| {
+        write_entry(
+            "fetched",
+            url,
+            &Fetched {
+                url: url.to_string(),
+                etag: validators.etag,
+                last_modified: validators.last_modified,
+                fetched_at: now(),
+                blob,
+            },
+        )
+    });
+
+    if let Err(e) = stored {
+        warn!("can't cache {}: {}", url.cyan(), e);
+    }
+}
+
+/// Looks up the output of processing, `key` covers the input and every option that affects it.
+pub fn processed(key: &str) -> Option<(Processed, Vec<u8>)> {
+    let entry = read_entry::<Processed>("processed", key)?;
+    let data = read_blob(&entry.blob)?;
+
+    Some((entry, data))
+}
+
+pub fn store_processed(key: &str, mime: &str, data: &[u8]) {
+    let stored = write_blob(data).and_then(|blob| {
+        write_entry(
+            "processed",
+            key,
+            &Processed {
+                mime: mime.to_string(),
+                blob,
+            },
+        )
+    });
+
+    if let Err(e) = stored {
+        warn!("can't write to the cache: {}", e);
+    }
+}
+
+/// Removes every cached file.
+pub fn clear() -> Result<()> {
+    let dir = paths::dirs::cache();
+
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+
+    Ok(())
+}
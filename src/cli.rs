@@ -1,4 +1,4 @@
-use crate::{die, document, error, ioutil, note, themes};
+use crate::{assets, die, document, error, highlight, images, ioutil, note, themes};
 use clap::{ArgGroup, Command, Parser, ValueHint};
 use clap_complete::{Generator, Shell};
 use colored::Colorize;
@@ -40,6 +40,13 @@ pub struct Cli {
     #[arg(long, group = "info", help = "Print config path")]
     pub where_config: bool,
 
+    #[arg(
+        long,
+        group = "info",
+        help = "Remove cached downloads and optimized images"
+    )]
+    pub clear_cache: bool,
+
     #[arg(short, long, help = "Output file", value_hint = ValueHint::FilePath)]
     pub out: Option<PathBuf>,
 
@@ -53,9 +60,32 @@ pub struct Cli {
     )]
     pub highlight: bool,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Where code is highlighted, server renders colored spans without JavaScript"
+    )]
+    pub highlighter: document::Renderer,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Color scheme for server-side highlighting [default: matches the theme]"
+    )]
+    pub highlight_scheme: Option<highlight::ColorScheme>,
+
     #[arg(short = 'M', long, help = "Enable math rendering with KaTeX")]
     pub math: bool,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Where math is rendered, server converts it to MathML without JavaScript"
+    )]
+    pub math_renderer: document::Renderer,
+
     #[arg(short = 'D', long, help = "Enable UML diagrams rendering with Mermaid")]
     pub diagrams: bool,
 
@@ -63,10 +93,33 @@ pub struct Cli {
         short = 'I',
         long,
         value_enum,
-        help = "Include images into file as base64 encoded"
+        help = "Include images, media, icons and stylesheets into file as base64 encoded"
     )]
     pub include_images: Option<document::IncludeLevel>,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Where local images and media end up, copy puts them into assets/ next to the output"
+    )]
+    pub assets: assets::AssetMode,
+
+    #[arg(
+        long,
+        help = "Write resized variants of local images into assets/ and serve them with srcset"
+    )]
+    pub responsive_images: bool,
+
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        value_delimiter = ',',
+        default_value = "480,960,1440",
+        help = "Widths of the responsive image variants"
+    )]
+    pub image_widths: Vec<u32>,
+
     #[arg(
         short = 'z',
         long,
@@ -74,6 +127,87 @@ pub struct Cli {
     )]
     pub optimize_images: bool,
 
+    #[arg(
+        long,
+        value_enum,
+        help = "Convert included images to this format [default: keep the original]"
+    )]
+    pub image_format: Option<images::ImageFormat>,
+
+    #[arg(
+        long,
+        default_value = "80",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "Quality of converted JPEG and WebP images, from 1 to 100"
+    )]
+    pub image_quality: u8,
+
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        help = "Downscale included images wider than this, keeping the aspect ratio"
+    )]
+    pub max_image_width: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        help = "Downscale included images taller than this, keeping the aspect ratio"
+    )]
+    pub max_image_height: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        help = "Timeout of connecting to a server when downloading"
+    )]
+    pub connect_timeout: u64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "30",
+        help = "Timeout of a whole download, including connecting and reading the response"
+    )]
+    pub fetch_timeout: u64,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "20000000",
+        help = "Skip downloads larger than this"
+    )]
+    pub max_fetch_size: u64,
+
+    #[arg(
+        long,
+        value_name = "HOST",
+        help = "Only download from these hosts, `*.example.com` matches subdomains"
+    )]
+    pub allow_host: Vec<String>,
+
+    #[arg(long, value_name = "HOST", help = "Never download from these hosts")]
+    pub deny_host: Vec<String>,
+
+    #[arg(long, help = "Don't download anything, use cached copies only")]
+    pub offline: bool,
+
+    #[arg(
+        long,
+        help = "Embed highlight.js, KaTeX and Mermaid instead of loading them from a CDN"
+    )]
+    pub self_contained: bool,
+
+    #[arg(
+        long,
+        help = "Sanitize raw HTML against the allowlist in sanitize.toml of the config directory"
+    )]
+    pub safe: bool,
+
+    #[arg(long, help = "Show permalink anchors next to headings on hover")]
+    pub permalinks: bool,
+
     #[arg(short = 'A', long, help = "Enable all extra renderers")]
     pub all: bool,
 
@@ -86,6 +220,21 @@ pub struct Cli {
     #[arg(long, default_value = "8080", help = "Port of the live server")]
     pub port: u16,
 
+    #[arg(
+        long,
+        env = "MARKY_EDITOR",
+        value_name = "COMMAND",
+        help = "Command opening the source when a block of the live preview is double-clicked, like `code -g {file}:{line}` [default: $EDITOR +{line} {file}]"
+    )]
+    pub editor: Option<String>,
+
+    #[arg(
+        long,
+        requires = "live",
+        help = "Update the live preview from editor buffers sent as newline-delimited JSON on stdin, like {\"text\": \"# Title\"}"
+    )]
+    pub stdin_buffer: bool,
+
     #[arg(short = 'O', long, help = "Open output file in the default app")]
     pub open: bool,
 }
@@ -101,7 +250,7 @@ impl Cli {
         }
 
         if let Some(path) = &self.path {
-            return ioutil::read_path(&path);
+            return ioutil::read_path(path);
         }
 
         if let Some(string) = &self.string {
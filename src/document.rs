@@ -1,31 +1,63 @@
-use std::fs;
+use std::collections::BTreeMap;
 
-use crate::info;
+use crate::warn;
 use anyhow::Result;
 use colored::Colorize;
 use handlebars::Handlebars;
-use image::{DynamicImage, ImageOutputFormat};
-use lol_html::{element, HtmlRewriter, Settings};
 use serde::Serialize;
-use std::ffi::OsStr;
-use std::io::Cursor;
-use std::path::Path;
-
+use std::path::{Path, PathBuf};
+
+use crate::alerts;
+use crate::anchors;
+use crate::assets::{self, AssetMode};
+use crate::fetch::FetchPolicy;
+use crate::frontmatter::FrontMatter;
+use crate::highlight::{self, ColorScheme};
+use crate::images::{ImageFormat, ImageOptions};
 use crate::included::{TEMPLATES_DIR, VENDOR_DIR};
-use crate::themes::Theme;
+use crate::libs::Vendored;
+use crate::lines;
+use crate::math;
+use crate::responsive;
+use crate::sanitize::{self, Allowlist};
+use crate::site::Navigation;
+use crate::themes::{self, Theme};
+use crate::toc;
+use crate::urls;
 
 pub struct Document {
     pub text: String,
     pub options: RenderOptions,
+    pub front_matter: FrontMatter,
+    /// Set when the document is a page of a site.
+    pub navigation: Option<Navigation>,
+    /// Directory relative paths of the document are resolved against.
+    pub base_dir: PathBuf,
+    /// Directory the rendered page is written to, relative references are rebased onto it.
+    pub out_dir: Option<PathBuf>,
+    /// Files included into the text, watched along with the source.
+    pub includes: Vec<PathBuf>,
 }
 
-#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum IncludeLevel {
     Local,
     Remote,
     All,
 }
 
+/// Where an extension does its work.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Renderer {
+    /// In the browser with JavaScript
+    #[default]
+    Client,
+    /// At render time, the output needs no scripts
+    Server,
+}
+
 #[derive(Clone)]
 pub struct RenderOptions {
     pub theme: Theme,
@@ -35,22 +67,121 @@ pub struct RenderOptions {
     pub live: bool,
     pub include_images: Option<IncludeLevel>,
     pub optimize_images: bool,
+    pub image_format: Option<ImageFormat>,
+    pub image_quality: u8,
+    pub max_image_width: Option<u32>,
+    pub max_image_height: Option<u32>,
+    pub fetch: FetchPolicy,
+    pub assets: AssetMode,
+    pub responsive_images: bool,
+    pub image_widths: Vec<u32>,
+    /// Raw HTML is sanitized against the allowlist when set.
+    pub safe: Option<Allowlist>,
+    pub permalinks: bool,
+    pub self_contained: bool,
+    pub highlighter: Renderer,
+    pub highlight_scheme: Option<ColorScheme>,
+    pub math_renderer: Renderer,
+}
+
+impl RenderOptions {
+    /// Whether code is highlighted by highlight.js in the browser.
+    pub fn client_highlight(&self) -> bool {
+        self.highlight && self.highlighter == Renderer::Client
+    }
+
+    fn server_highlight(&self) -> bool {
+        self.highlight && self.highlighter == Renderer::Server
+    }
+
+    /// Whether math is rendered by KaTeX in the browser.
+    pub fn client_math(&self) -> bool {
+        self.math && self.math_renderer == Renderer::Client
+    }
+
+    fn server_math(&self) -> bool {
+        self.math && self.math_renderer == Renderer::Server
+    }
+
+    pub fn image_options(&self) -> ImageOptions {
+        ImageOptions {
+            format: self.image_format,
+            quality: self.image_quality,
+            optimize: self.optimize_images,
+            max_width: self.max_image_width,
+            max_height: self.max_image_height,
+        }
+    }
 }
 
 #[derive(Serialize)]
-pub struct TemplateData {
+pub struct TemplateData<'a> {
     pub theme: String,
     pub highlight: bool,
+    pub highlight_css: Option<String>,
     pub math: bool,
     pub diagrams: bool,
     pub compiled: String,
+    pub toc: String,
     pub title: String,
     pub websocket: String,
     pub script: String,
     pub live: bool,
+    pub permalinks: bool,
+    pub meta: BTreeMap<String, serde_json::Value>,
+    pub vendored: Vendored,
+    pub site: Option<&'a Navigation>,
 }
 
 impl Document {
+    /// Creates a document, letting its front matter override the given render options.
+    pub fn new(text: String, mut options: RenderOptions) -> Self {
+        let front_matter = match FrontMatter::parse(&text) {
+            Ok(front_matter) => front_matter.unwrap_or_default(),
+            Err(e) => {
+                warn!("invalid front matter: {}", e);
+                FrontMatter::default()
+            }
+        };
+
+        if let Some(name) = &front_matter.theme {
+            match themes::available_themes().map(|themes| themes.by_name(name)) {
+                Ok(Some(theme)) => options.theme = theme,
+                Ok(None) => warn!("unknown theme {} in front matter", name.cyan()),
+                Err(e) => warn!("can't load themes: {}", e),
+            }
+        }
+
+        if let Some(highlight) = front_matter.highlight {
+            options.highlight = highlight;
+        }
+
+        if let Some(math) = front_matter.math {
+            options.math = math;
+        }
+
+        if let Some(diagrams) = front_matter.diagrams {
+            options.diagrams = diagrams;
+        }
+
+        // Untrusted documents can't ask to read files into the output
+        if front_matter.include_images.is_some() && options.safe.is_some() {
+            warn!("ignoring include_images of the front matter in safe mode");
+        } else if front_matter.include_images.is_some() {
+            options.include_images = front_matter.include_images;
+        }
+
+        Document {
+            text,
+            options,
+            front_matter,
+            navigation: None,
+            base_dir: PathBuf::new(),
+            out_dir: None,
+            includes: Vec::new(),
+        }
+    }
+
     fn handlebars() -> Handlebars<'static> {
         let mut reg = Handlebars::new();
         let template_string = TEMPLATES_DIR
@@ -65,19 +196,24 @@ impl Document {
         reg
     }
 
+    fn parse_options(&self) -> markdown::ParseOptions {
+        markdown::ParseOptions {
+            constructs: markdown::Constructs {
+                html_flow: true,
+                html_text: true,
+                math_flow: self.options.math,
+                math_text: self.options.math,
+                definition: true,
+                frontmatter: true,
+                ..markdown::Constructs::gfm()
+            },
+            ..markdown::ParseOptions::gfm()
+        }
+    }
+
     pub fn render_body(&self) -> String {
         let markdown_options = markdown::Options {
-            parse: markdown::ParseOptions {
-                constructs: markdown::Constructs {
-                    html_flow: true,
-                    html_text: true,
-                    math_flow: self.options.math,
-                    math_text: self.options.math,
-                    definition: true,
-                    ..markdown::Constructs::gfm()
-                },
-                ..markdown::ParseOptions::gfm()
-            },
+            parse: self.parse_options(),
             compile: markdown::CompileOptions {
                 allow_dangerous_html: true,
                 ..markdown::CompileOptions::gfm()
@@ -87,16 +223,94 @@ impl Document {
         let html = markdown::to_html_with_options(self.text.as_str(), &markdown_options)
             .expect("never errors with MDX disabled");
 
-        if self.options.include_images.is_some() {
-            self.include_images(html).unwrap() // TODO
+        // Before anything else, the markup added later is trusted
+        let html = match &self.options.safe {
+            Some(allowlist) => {
+                sanitize::sanitize(&html, allowlist).expect("sanitizer must not fail")
+            }
+            None => html,
+        };
+
+        let root = self.mdast();
+
+        // Lets an editor scroll the preview to the line of its cursor
+        let html = match &root {
+            Some(root) if self.options.live => {
+                lines::annotate(&html, root).expect("line rewriter must not fail")
+            }
+            _ => html,
+        };
+
+        let html = if self.options.server_highlight() {
+            highlight::highlight_code_blocks(&html).expect("highlight rewriter must not fail")
+        } else {
+            html
+        };
+
+        let html = if self.options.server_math() {
+            math::render_mathml(&html).expect("math rewriter must not fail")
+        } else {
+            html
+        };
+
+        let alerts = root.as_ref().map(alerts::alerts).unwrap_or_default();
+        let html = alerts::render_alerts(&html, &alerts).expect("alert rewriter must not fail");
+
+        let headings = root.as_ref().map(toc::headings).unwrap_or_default();
+        let html = toc::fill(html, &toc::render(&headings));
+        let html = match &root {
+            Some(root) => anchors::anchor_headings(&html, root, &headings, self.options.permalinks)
+                .expect("heading rewriter must not fail"),
+            None => html,
+        };
+
+        let html = if self.options.include_images.is_some() {
+            self.include_images(html)
+                .expect("image rewriter must not fail")
+        } else {
+            html
+        };
+
+        let out_dir = match &self.out_dir {
+            Some(out_dir) => out_dir,
+            None => return html,
+        };
+
+        // Links between pages of a site are rewritten by the site itself
+        let html = match urls::rebase(&html, &self.base_dir, out_dir, self.navigation.is_some()) {
+            Ok(rebased) => rebased,
+            Err(e) => {
+                warn!("can't rebase relative paths: {}", e);
+                html
+            }
+        };
+
+        let html = if self.options.assets == AssetMode::Copy {
+            assets::copy(&html, out_dir, self.confine()).expect("asset rewriter must not fail")
+        } else {
+            html
+        };
+
+        if self.options.responsive_images {
+            responsive::responsive_images(
+                &html,
+                out_dir,
+                &self.options.image_widths,
+                &self.options.image_options(),
+                self.confine(),
+            )
+            .expect("image rewriter must not fail")
         } else {
             html
         }
     }
 
     pub fn render(&self) -> Result<Vec<u8>> {
-        let body = self.render_body();
+        self.render_page(self.render_body())
+    }
 
+    /// Renders the page around an already rendered body.
+    pub fn render_page(&self, body: String) -> Result<Vec<u8>> {
         let script: String = {
             let mut minified_script = Vec::new();
             let script = VENDOR_DIR
@@ -119,12 +333,22 @@ impl Document {
             "html",
             &TemplateData {
                 theme: self.options.theme.resolve()?,
-                highlight: self.options.highlight,
-                math: self.options.math,
+                highlight: self.options.client_highlight(),
+                highlight_css: self.highlight_css()?,
+                math: self.options.client_math(),
                 diagrams: self.options.diagrams,
                 compiled: body,
+                toc: self.toc(),
                 title: self.title().unwrap_or("Document".into()),
                 live: self.options.live,
+                permalinks: self.options.permalinks,
+                meta: self.front_matter.meta.clone(),
+                site: self.navigation.as_ref(),
+                vendored: if self.options.self_contained {
+                    Vendored::load(&self.options)?
+                } else {
+                    Vendored::default()
+                },
                 websocket: VENDOR_DIR
                     .get_file("js/reconnecting-websocket.js")
                     .unwrap()
@@ -138,8 +362,41 @@ impl Document {
         Ok(html.into_bytes())
     }
 
+    /// Colors of server-side highlighting, `None` when code isn't highlighted at render time.
+    pub fn highlight_css(&self) -> Result<Option<String>> {
+        if !self.options.server_highlight() {
+            return Ok(None);
+        }
+
+        let scheme = self
+            .options
+            .highlight_scheme
+            .unwrap_or_else(|| ColorScheme::for_theme(&self.options.theme));
+
+        Ok(Some(scheme.css()?))
+    }
+
+    fn mdast(&self) -> Option<markdown::mdast::Node> {
+        markdown::to_mdast(&self.text, &self.parse_options()).ok()
+    }
+
+    fn headings(&self) -> Vec<toc::Heading> {
+        self.mdast()
+            .map(|root| toc::headings(&root))
+            .unwrap_or_default()
+    }
+
+    /// Table of contents built from the document headings.
+    pub fn toc(&self) -> String {
+        toc::render(&self.headings())
+    }
+
     pub fn title(&self) -> Option<String> {
-        match markdown::to_mdast(&self.text, &markdown::ParseOptions::gfm()) {
+        if let Some(title) = &self.front_matter.title {
+            return Some(title.clone());
+        }
+
+        match markdown::to_mdast(&self.text, &self.parse_options()) {
             Ok(node) => Document::get_title_from_node(&node),
             Err(_) => None,
         }
@@ -149,118 +406,26 @@ impl Document {
         match node {
             markdown::mdast::Node::Heading(_) => Some(node.to_string()),
             _ => match node.children() {
-                Some(children) => {
-                    for child in children.iter() {
-                        if let Some(title) = Self::get_title_from_node(&child) {
-                            return Some(title);
-                        }
-
-                        return None;
-                    }
-
-                    None
-                }
+                Some(children) => children
+                    .iter()
+                    .find(|child| {
+                        !matches!(
+                            child,
+                            markdown::mdast::Node::Yaml(_) | markdown::mdast::Node::Toml(_)
+                        )
+                    })
+                    .and_then(Self::get_title_from_node),
                 None => None,
             },
         }
     }
 
     fn include_images(&self, html_page: String) -> anyhow::Result<String> {
-        let mut output = vec![];
-
-        let mut rewriter = HtmlRewriter::new(
-            Settings {
-                element_content_handlers: vec![element!("img[src]", |el| {
-                    let src = el.get_attribute("src").expect("src was required");
-
-                    let data = {
-                        let level = self.options.include_images.expect("must be not none");
-
-                        let include_remote =
-                            level == IncludeLevel::All || level == IncludeLevel::Remote;
-
-                        let include_local =
-                            level == IncludeLevel::All || level == IncludeLevel::Local;
-
-                        let is_remote = src.starts_with("http");
-
-                        if is_remote && include_remote {
-                            info!("Downloading {}", src);
-
-                            if src.ends_with(".svg") {
-                                let svg_data = download_image(src.as_str())?;
-                                let base64_svg = base64::encode(&svg_data);
-                                el.set_attribute(
-                                    "src",
-                                    &format!("data:image/svg+xml;base64,{}", base64_svg),
-                                )?;
-                                None
-                            } else {
-                                Some(download_image(src.as_str())?)
-                            }
-                        } else if !is_remote && include_local {
-                            info!("Reading {}", src);
-
-                            let path = Path::new(&src);
-                            let is_svg = path.extension() == Some(OsStr::new("svg"));
-
-                            if is_svg {
-                                let svg_data = self.svg_to_base64(path)?;
-                                el.set_attribute("src", &svg_data)?;
-                                None
-                            } else {
-                                Some(fs::read(src)?)
-                            }
-                        } else {
-                            info!("Skipping {}", src);
-                            None
-                        }
-                    };
-
-                    if let Some(data) = data {
-                        info!("Encoding to base64",);
-                        let img = image::load_from_memory(data.as_slice())?;
-                        el.set_attribute("src", &self.image_to_base64(&img)?)?;
-                    }
-
-                    Ok(())
-                })],
-                ..Settings::default()
-            },
-            |c: &[u8]| output.extend_from_slice(c),
-        );
-
-        rewriter.write(html_page.as_bytes())?;
-        rewriter.end()?;
-
-        Ok(String::from_utf8(output)?)
-    }
-
-    fn image_to_base64(&self, img: &DynamicImage) -> anyhow::Result<String> {
-        let mut image_data: Vec<u8> = Vec::new();
-        img.write_to(&mut Cursor::new(&mut image_data), ImageOutputFormat::Png)
-            .unwrap();
-
-        if self.options.optimize_images {
-            info!("Optimizing image");
-            match oxipng::optimize_from_memory(image_data.as_slice(), &oxipng::Options::default()) {
-                Ok(optimized) => image_data = optimized,
-                Err(e) => return Err(anyhow::Error::new(e)),
-            }
-        }
-
-        let res_base64 = base64::encode(image_data);
-        Ok(format!("data:image/png;base64,{}", res_base64))
+        assets::embed(&html_page, &self.base_dir, &self.options, self.confine())
     }
 
-    fn svg_to_base64(&self, path: &Path) -> anyhow::Result<String> {
-        let svg_data = fs::read(path)?;
-        let base64_svg = base64::encode(svg_data);
-        let svg_data_uri = format!("data:image/svg+xml;base64,{}", base64_svg);
-        Ok(svg_data_uri)
+    /// Directory local files must be in, only set in safe mode.
+    fn confine(&self) -> Option<&Path> {
+        self.options.safe.as_ref().map(|_| self.base_dir.as_path())
     }
 }
-
-fn download_image(url: &str) -> anyhow::Result<Vec<u8>> {
-    Ok(reqwest::blocking::get(url)?.bytes()?.into())
-}
@@ -0,0 +1,44 @@
+use std::path::Path;
+use std::process::Command;
+use std::{env, thread};
+
+use anyhow::{anyhow, Result};
+
+/// Command template used when only `$EDITOR` is set, understood by vim, emacs, nano and others.
+const EDITOR_TEMPLATE: &str = "{editor} +{line} {file}";
+
+/// Command opening a file at a line, from `--editor` or `$EDITOR`.
+pub fn template(editor: Option<&str>) -> Option<String> {
+    match editor {
+        Some(editor) => Some(editor.to_string()),
+        None => env::var("EDITOR")
+            .ok()
+            .filter(|editor| !editor.trim().is_empty())
+            .map(|editor| EDITOR_TEMPLATE.replace("{editor}", &editor)),
+    }
+}
+
+/// Runs the command template with `{file}` and `{line}` replaced, without waiting for it.
+///
+/// The template is split on whitespace and never passed to a shell, so file names can't
+/// inject commands.
+pub fn open(template: &str, file: &Path, line: usize) -> Result<()> {
+    let file = file.display().to_string();
+    let line = line.to_string();
+
+    let mut args = template
+        .split_whitespace()
+        .map(|arg| arg.replace("{file}", &file).replace("{line}", &line));
+
+    let program = args
+        .next()
+        .ok_or_else(|| anyhow!("the editor command is empty"))?;
+    let mut child = Command::new(&program)
+        .args(args)
+        .spawn()
+        .map_err(|e| anyhow!("can't run {}: {}", program, e))?;
+
+    thread::spawn(move || child.wait());
+
+    Ok(())
+}
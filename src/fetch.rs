@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::time::Duration;
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Result};
+use colored::Colorize;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, LOCATION};
+use reqwest::header::{
+    CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::redirect::Policy;
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+
+use crate::cache::{self, Validators};
+use crate::{info, paths, warn};
+
+const MAX_REDIRECTS: usize = 10;
+
+/// How long responses without `ETag` or `Last-Modified` are used without downloading them again.
+const UNVALIDATED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Limits on downloading remote resources.
+#[derive(Clone, Debug)]
+pub struct FetchPolicy {
+    pub connect_timeout: Duration,
+    /// Timeout of the whole request, from connecting to reading the last byte.
+    pub timeout: Duration,
+    pub max_bytes: u64,
+    /// Hosts that may be fetched from, any host when empty.
+    pub allow_hosts: Vec<String>,
+    pub deny_hosts: Vec<String>,
+    /// Only cached copies are used.
+    pub offline: bool,
+    pub headers: Vec<HostHeaders>,
+}
+
+/// Headers sent to the hosts matching a pattern, like an `Authorization` token.
+///
+/// Values are marked sensitive, so they are never printed.
+#[derive(Clone, Debug)]
+pub struct HostHeaders {
+    host: String,
+    headers: HeaderMap,
+}
+
+#[derive(Deserialize)]
+struct HostsConfig {
+    #[serde(default)]
+    hosts: Vec<HostConfig>,
+}
+
+#[derive(Deserialize)]
+struct HostConfig {
+    host: String,
+    headers: BTreeMap<String, String>,
+}
+
+/// Reads per-host headers from `hosts.toml` in the config directory.
+///
+/// ```toml
+/// [[hosts]]
+/// host = "*.githubusercontent.com"
+/// headers = { Authorization = "token ${GITHUB_TOKEN}" }
+/// ```
+pub fn load_host_headers() -> Result<Vec<HostHeaders>> {
+    let path = paths::files::hosts();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let config: HostsConfig = toml::from_str(&fs::read_to_string(&path)?)?;
+
+    Ok(config
+        .hosts
+        .into_iter()
+        .map(|host| {
+            let mut headers = HeaderMap::new();
+
+            for (name, value) in host.headers {
+                let header = expand_env(&value).and_then(|value| {
+                    let mut value = HeaderValue::from_str(&value)?;
+                    value.set_sensitive(true);
+
+                    Ok((HeaderName::from_bytes(name.as_bytes())?, value))
+                });
+
+                match header {
+                    Ok((name, value)) => {
+                        headers.insert(name, value);
+                    }
+                    Err(e) => warn!(
+                        "skipping header {} for {}: {}",
+                        name.cyan(),
+                        host.host.cyan(),
+                        e
+                    ),
+                }
+            }
+
+            HostHeaders {
+                host: host.host,
+                headers,
+            }
+        })
+        .collect())
+}
+
+/// Replaces `${NAME}` with the value of the environment variable.
+fn expand_env(value: &str) -> Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("unclosed ${{ in header value"))?;
+
+        let name = &rest[start + 2..end];
+        let variable =
+            env::var(name).map_err(|_| anyhow!("environment variable {} is not set", name))?;
+
+        result.push_str(&rest[..start]);
+        result.push_str(&variable);
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+impl FetchPolicy {
+    fn check_host(&self, url: &str) -> Result<()> {
+        let url = Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("{} has no host", url))?
+            .to_ascii_lowercase();
+
+        if self
+            .deny_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, &host))
+        {
+            bail!("host {} is denied", host);
+        }
+
+        if !self.allow_hosts.is_empty()
+            && !self
+                .allow_hosts
+                .iter()
+                .any(|pattern| host_matches(pattern, &host))
+        {
+            bail!("host {} is not allowed", host);
+        }
+
+        Ok(())
+    }
+
+    fn headers_for(&self, url: &Url) -> impl Iterator<Item = (&HeaderName, &HeaderValue)> {
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+
+        self.headers
+            .iter()
+            .filter(move |headers| host_matches(&headers.host, &host))
+            .flat_map(|headers| headers.headers.iter())
+    }
+}
+
+/// `example.com` matches the host itself, `*.example.com` any of its subdomains.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .map(|subdomain| subdomain.ends_with('.'))
+            .unwrap_or(false),
+        None => host == pattern,
+    }
+}
+
+/// Downloads a URL, revalidating a cached copy with its `ETag` or `Last-Modified`.
+///
+/// Copies without either are reused for a day instead. `media` is the expected type of the
+/// content, like `image/`. The cached copy is used when the server can't be reached.
+pub fn fetch(url: &str, media: &str, policy: &FetchPolicy) -> Result<Vec<u8>> {
+    policy.check_host(url)?;
+
+    // The blocking client panics when used from within the async runtime
+    let data = std::thread::scope(|scope| {
+        scope
+            .spawn(|| fetch_blocking(url, media, policy))
+            .join()
+            .expect("fetch thread must not panic")
+    })?;
+
+    // Cached copies may predate a lower limit
+    if data.len() as u64 > policy.max_bytes {
+        bail!("larger than {} bytes", policy.max_bytes);
+    }
+
+    Ok(data)
+}
+
+fn fetch_blocking(url: &str, media: &str, policy: &FetchPolicy) -> Result<Vec<u8>> {
+    let cached = cache::fetched(url);
+
+    if policy.offline {
+        return match cached {
+            Some(cached) => Ok(cached.data),
+            None => bail!("not cached, can't download in offline mode"),
+        };
+    }
+
+    if let Some(cached) = &cached {
+        if cached.validators.is_empty() && cached.age < UNVALIDATED_TTL {
+            info!("Using cached {}", url);
+            return Ok(cached.data.clone());
+        }
+    }
+
+    let client = Client::builder()
+        .connect_timeout(policy.connect_timeout)
+        .timeout(policy.timeout)
+        .redirect(Policy::none())
+        .build()?;
+
+    let validators = cached.as_ref().map(|cached| &cached.validators);
+    let response = match send(&client, url, validators, policy) {
+        Ok(response) => response,
+        Err(e) => match cached {
+            Some(cached) => {
+                warn!("can't reach {}, using the cached copy: {}", url.cyan(), e);
+                return Ok(cached.data);
+            }
+            None => return Err(e),
+        },
+    };
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(cached) = cached {
+            info!("Using cached {}", url);
+            return Ok(cached.data);
+        }
+    }
+
+    let response = response.error_for_status()?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+            .map(str::to_string)
+    };
+
+    // Servers often answer with an HTML error or login page
+    if let Some(content_type) = header(CONTENT_TYPE) {
+        let content_type = content_type.to_ascii_lowercase();
+        if !content_type.starts_with(media) && !content_type.starts_with("application/octet-stream")
+        {
+            bail!("expected {}*, got {}", media, content_type);
+        }
+    }
+
+    let length = header(CONTENT_LENGTH).and_then(|length| length.parse::<u64>().ok());
+    if length
+        .map(|length| length > policy.max_bytes)
+        .unwrap_or(false)
+    {
+        bail!("larger than {} bytes", policy.max_bytes);
+    }
+
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+
+    let mut data = Vec::new();
+    response.take(policy.max_bytes + 1).read_to_end(&mut data)?;
+
+    if data.len() as u64 > policy.max_bytes {
+        bail!("larger than {} bytes", policy.max_bytes);
+    }
+
+    cache::store_fetched(url, validators, &data);
+
+    Ok(data)
+}
+
+/// Sends the request, following redirects manually so that every hop gets only the headers
+/// configured for its own host and is checked against the host lists.
+fn send(
+    client: &Client,
+    url: &str,
+    validators: Option<&Validators>,
+    policy: &FetchPolicy,
+) -> Result<Response> {
+    let mut url = Url::parse(url)?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let mut request = client.get(url.clone());
+
+        if let Some(validators) = validators {
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        for (name, value) in policy.headers_for(&url) {
+            request = request.header(name, value);
+        }
+
+        let response = request.send()?;
+        if !response.status().is_redirection() || response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(response);
+        }
+
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| anyhow!("redirect without a location"))?;
+
+        url = url.join(location)?;
+        policy.check_host(url.as_str())?;
+    }
+
+    bail!("more than {} redirects", MAX_REDIRECTS)
+}
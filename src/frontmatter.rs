@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+
+use markdown::mdast::Node;
+use serde::Deserialize;
+
+use crate::document::IncludeLevel;
+
+/// Document metadata declared in a YAML (`---`) or TOML (`+++`) block at the top of the file.
+#[derive(Deserialize, Default)]
+pub struct FrontMatter {
+    pub title: Option<String>,
+    pub theme: Option<String>,
+    pub highlight: Option<bool>,
+    pub math: Option<bool>,
+    pub diagrams: Option<bool>,
+    #[serde(alias = "include-images")]
+    pub include_images: Option<IncludeLevel>,
+
+    /// Remaining keys, exposed to the template as `meta`.
+    #[serde(flatten)]
+    pub meta: BTreeMap<String, serde_json::Value>,
+}
+
+impl FrontMatter {
+    pub fn parse(text: &str) -> anyhow::Result<Option<Self>> {
+        let options = markdown::ParseOptions {
+            constructs: markdown::Constructs {
+                frontmatter: true,
+                ..markdown::Constructs::gfm()
+            },
+            ..markdown::ParseOptions::gfm()
+        };
+
+        let root = match markdown::to_mdast(text, &options) {
+            Ok(root) => root,
+            Err(_) => return Ok(None),
+        };
+
+        let front_matter = match root.children().and_then(|children| children.first()) {
+            Some(Node::Yaml(yaml)) => Self::from_yaml(&yaml.value)?,
+            Some(Node::Toml(toml)) => toml::from_str(&toml.value)?,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(front_matter))
+    }
+
+    fn from_yaml(value: &str) -> anyhow::Result<Self> {
+        // An empty block deserializes to a unit, not a map
+        if value.trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        Ok(serde_yaml::from_str(value)?)
+    }
+}
@@ -0,0 +1,163 @@
+use std::cell::RefCell;
+use std::sync::OnceLock;
+
+use lol_html::{element, html_content::ContentType, text, HtmlRewriter, Settings};
+use syntect::highlighting::ThemeSet;
+use syntect::html::{ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+use crate::html;
+use crate::themes::Theme;
+
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Code blocks rendered by other extensions, never highlighted.
+const RESERVED_LANGUAGES: [&str; 2] = ["math", "mermaid"];
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ColorScheme {
+    InspiredGithub,
+    SolarizedLight,
+    SolarizedDark,
+    OceanLight,
+    OceanDark,
+    Eighties,
+    Mocha,
+}
+
+impl ColorScheme {
+    /// Picks a scheme that matches the page background of the theme.
+    pub fn for_theme(theme: &Theme) -> Self {
+        if theme.dark {
+            ColorScheme::OceanDark
+        } else {
+            ColorScheme::InspiredGithub
+        }
+    }
+
+    fn syntect_name(&self) -> &'static str {
+        match self {
+            ColorScheme::InspiredGithub => "InspiredGitHub",
+            ColorScheme::SolarizedLight => "Solarized (light)",
+            ColorScheme::SolarizedDark => "Solarized (dark)",
+            ColorScheme::OceanLight => "base16-ocean.light",
+            ColorScheme::OceanDark => "base16-ocean.dark",
+            ColorScheme::Eighties => "base16-eighties.dark",
+            ColorScheme::Mocha => "base16-mocha.dark",
+        }
+    }
+
+    /// Stylesheet for the spans produced by [`highlight_code_blocks`].
+    pub fn css(&self) -> anyhow::Result<String> {
+        let theme = theme_set()
+            .themes
+            .get(self.syntect_name())
+            .expect("scheme must be one of the syntect defaults");
+
+        let mut css = syntect::html::css_for_theme_with_class_style(theme, CLASS_STYLE)?;
+
+        // The block background is set on the <pre> so that its padding is colored too
+        if let Some(bg) = theme.settings.background {
+            css.push_str(&format!(
+                "pre:has(> code.hl-code) {{ background-color: #{:02x}{:02x}{:02x}; }}\n",
+                bg.r, bg.g, bg.b
+            ));
+        }
+
+        let result = minifier::css::minify(css.as_str())
+            .map(|m| m.to_string())
+            .unwrap_or(css);
+
+        Ok(result)
+    }
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// Rewrites `<pre><code class="language-*">` blocks into spans colored by the scheme stylesheet.
+pub fn highlight_code_blocks(html_page: &str) -> anyhow::Result<String> {
+    let mut output = vec![];
+
+    let language: RefCell<Option<String>> = RefCell::new(None);
+    let code = RefCell::new(String::new());
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                element!("pre > code[class^=\"language-\"]", |el| {
+                    let lang = el
+                        .get_attribute("class")
+                        .expect("class was required")
+                        .split_whitespace()
+                        .find_map(|class| class.strip_prefix("language-").map(str::to_string))
+                        .filter(|lang| !RESERVED_LANGUAGES.contains(&lang.as_str()))
+                        .filter(|lang| syntax_set().find_syntax_by_token(lang).is_some());
+
+                    if lang.is_some() {
+                        el.set_attribute(
+                            "class",
+                            &format!("{} hl-code", el.get_attribute("class").unwrap()),
+                        )?;
+                    }
+
+                    language.replace(lang);
+                    code.borrow_mut().clear();
+
+                    Ok(())
+                }),
+                text!("pre > code[class^=\"language-\"]", |chunk| {
+                    let lang = language.borrow();
+                    let lang = match lang.as_ref() {
+                        Some(lang) => lang,
+                        None => return Ok(()),
+                    };
+
+                    code.borrow_mut().push_str(chunk.as_str());
+
+                    if !chunk.last_in_text_node() {
+                        chunk.remove();
+                        return Ok(());
+                    }
+
+                    let source = html::unescape(&code.borrow());
+                    chunk.replace(&highlight(&source, lang)?, ContentType::Html);
+
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
+
+fn highlight(source: &str, lang: &str) -> anyhow::Result<String> {
+    let syntax_set = syntax_set();
+    let syntax = syntax_set
+        .find_syntax_by_token(lang)
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, syntax_set, CLASS_STYLE);
+
+    for line in LinesWithEndings::from(source) {
+        generator.parse_html_for_line_which_includes_newline(line)?;
+    }
+
+    Ok(generator.finalize())
+}
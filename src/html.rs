@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use lol_html::{element, HtmlRewriter, Settings};
+use markdown::mdast::Node;
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+pub fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Number of elements matching the selector in a fragment of raw HTML.
+pub fn count_elements(html: &str, selector: &str) -> usize {
+    let mut count = 0;
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!(selector, |_| {
+                count += 1;
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+
+    if rewriter.write(html.as_bytes()).is_err() || rewriter.end().is_err() {
+        return 0;
+    }
+
+    count
+}
+
+/// Heading markdown-rs starts the footnotes with.
+const FOOTNOTES_LABEL: &str = "<h2 id=\"footnote-label\" class=\"sr-only\">Footnotes</h2>";
+
+/// Nodes of a kind in the order markdown-rs renders them, with `None` for each element of raw
+/// HTML matching the selector, so rendered elements can be paired with their source by position.
+///
+/// Footnote definitions are rendered after the document, in the order they are first referenced.
+pub fn rendered_order<'a>(
+    root: &'a Node,
+    kind: fn(&Node) -> bool,
+    selector: &str,
+) -> Vec<Option<&'a Node>> {
+    let mut order = RenderedOrder {
+        kind,
+        selector,
+        definitions: HashMap::new(),
+        referenced: Vec::new(),
+        nodes: Vec::new(),
+    };
+
+    order.collect_definitions(root);
+    order.visit(root);
+
+    if !order.referenced.is_empty() {
+        order.raw(FOOTNOTES_LABEL);
+    }
+
+    let mut next = 0;
+    while let Some(identifier) = order.referenced.get(next) {
+        if let Some(definition) = order.definitions.get(identifier.as_str()).copied() {
+            for child in definition.children().into_iter().flatten() {
+                order.visit(child);
+            }
+        }
+
+        next += 1;
+    }
+
+    order.nodes
+}
+
+struct RenderedOrder<'a, 's> {
+    kind: fn(&Node) -> bool,
+    selector: &'s str,
+    definitions: HashMap<&'a str, &'a Node>,
+    referenced: Vec<String>,
+    nodes: Vec<Option<&'a Node>>,
+}
+
+impl<'a, 's> RenderedOrder<'a, 's> {
+    fn collect_definitions(&mut self, node: &'a Node) {
+        if let Node::FootnoteDefinition(definition) = node {
+            self.definitions
+                .entry(definition.identifier.as_str())
+                .or_insert(node);
+        }
+
+        for child in node.children().into_iter().flatten() {
+            self.collect_definitions(child);
+        }
+    }
+
+    fn raw(&mut self, html: &str) {
+        let count = count_elements(html, self.selector);
+        self.nodes.extend(std::iter::repeat_n(None, count));
+    }
+
+    fn visit(&mut self, node: &'a Node) {
+        match node {
+            Node::FootnoteDefinition(_) => return,
+            Node::FootnoteReference(reference) => {
+                let defined = self.definitions.contains_key(reference.identifier.as_str());
+                if defined && !self.referenced.contains(&reference.identifier) {
+                    self.referenced.push(reference.identifier.clone());
+                }
+            }
+            Node::Html(html) => self.raw(&html.value),
+            _ if (self.kind)(node) => self.nodes.push(Some(node)),
+            _ => {}
+        }
+
+        for child in node.children().into_iter().flatten() {
+            self.visit(child);
+        }
+    }
+}
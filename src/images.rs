@@ -0,0 +1,367 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use colored::Colorize;
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::imageops::FilterType;
+use image::{AnimationDecoder, DynamicImage, ImageOutputFormat};
+
+use crate::{cache, info, warn};
+
+/// Format included images are transcoded to.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    /// Format to re-encode a resized image of the given type in.
+    fn closest(mime: &str) -> Self {
+        match mime {
+            "image/jpeg" => ImageFormat::Jpeg,
+            "image/webp" => ImageFormat::Webp,
+            _ => ImageFormat::Png,
+        }
+    }
+
+    fn mime(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImageOptions {
+    /// Keep the source format when not set.
+    pub format: Option<ImageFormat>,
+    /// Lossy encoding quality, 1 to 100.
+    pub quality: u8,
+    /// Run PNG output through oxipng.
+    pub optimize: bool,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+}
+
+impl ImageOptions {
+    /// Maximum size of an image, never smaller than the size it is displayed at.
+    ///
+    /// Displaying an image larger than one limit raises both by the same factor, the other limit
+    /// would shrink it below its displayed size otherwise.
+    fn bounds(&self, size: DisplaySize) -> (u32, u32) {
+        let scale = |max: Option<u32>, displayed: Option<u32>| match (max, displayed) {
+            (Some(max), Some(displayed)) if displayed > max => displayed as f64 / max as f64,
+            _ => 1.0,
+        };
+        let scale = scale(self.max_width, size.width).max(scale(self.max_height, size.height));
+
+        let bound = |max: Option<u32>, displayed: Option<u32>| match max {
+            Some(max) => ((max as f64 * scale).ceil() as u32).max(displayed.unwrap_or(0)),
+            None => u32::MAX,
+        };
+
+        (
+            bound(self.max_width, size.width),
+            bound(self.max_height, size.height),
+        )
+    }
+}
+
+/// Image ready to be embedded.
+pub struct Image {
+    pub mime: &'static str,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn extension(&self) -> &'static str {
+        match self.mime {
+            "image/jpeg" => "jpg",
+            "image/x-icon" => "ico",
+            "image/svg+xml" => "svg",
+            mime => MIME_TYPES
+                .iter()
+                .find(|(_, known)| *known == mime)
+                .map(|(name, _)| *name)
+                .unwrap_or("bin"),
+        }
+    }
+
+    pub fn data_uri(&self) -> String {
+        format!("data:{};base64,{}", self.mime, base64::encode(&self.data))
+    }
+}
+
+/// Formats browsers display natively, these are embedded byte for byte.
+const MIME_TYPES: [(&str, &str); 8] = [
+    ("png", "image/png"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("svg", "image/svg+xml"),
+];
+
+/// Detects the MIME type from the leading bytes, falling back to the file extension of `name`.
+pub fn mime_type(data: &[u8], name: &str) -> Option<&'static str> {
+    let sniffed = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("webp")
+    } else if data.len() >= 12
+        && &data[4..8] == b"ftyp"
+        && matches!(&data[8..12], b"avif" | b"avis")
+    {
+        Some("avif")
+    } else if data.starts_with(&[0, 0, 1, 0]) {
+        Some("ico")
+    } else if data.starts_with(b"BM") {
+        Some("bmp")
+    } else if is_svg(data) {
+        Some("svg")
+    } else {
+        None
+    };
+
+    let extension = || {
+        let (path, _) = crate::urls::split_suffix(name);
+
+        match path.rsplit_once('.')?.1.to_ascii_lowercase().as_str() {
+            "jpg" => Some("jpeg"),
+            extension => MIME_TYPES
+                .iter()
+                .map(|(name, _)| *name)
+                .find(|name| *name == extension),
+        }
+    };
+
+    let format = sniffed.or_else(extension)?;
+
+    MIME_TYPES
+        .iter()
+        .find(|(name, _)| *name == format)
+        .map(|(_, mime)| *mime)
+}
+
+fn is_svg(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(512)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    head.starts_with("<svg") || (head.starts_with("<?xml") && head.contains("<svg"))
+}
+
+/// Size of an `<img>` as set by its `width` and `height` attributes, in pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DisplaySize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl DisplaySize {
+    /// Parses attribute values like `300` or `300px`, percentages are ignored.
+    pub fn parse(width: Option<String>, height: Option<String>) -> Self {
+        let pixels = |value: Option<String>| {
+            let value = value?;
+            let value = value.trim();
+            value
+                .strip_suffix("px")
+                .unwrap_or(value)
+                .trim()
+                .parse()
+                .ok()
+        };
+
+        DisplaySize {
+            width: pixels(width),
+            height: pixels(height),
+        }
+    }
+}
+
+/// Prepares an image for embedding, `name` is its path or URL.
+///
+/// Converted and optimized images are cached, keyed by their content and the options.
+pub fn prepare(
+    data: Vec<u8>,
+    name: &str,
+    size: DisplaySize,
+    options: &ImageOptions,
+) -> Result<Image> {
+    let input = cache::hash(&data);
+    let key = format!(
+        "{}:{:?}:{:?}:{}:{}:{:?}",
+        input,
+        mime_type(&data, name),
+        options.format,
+        options.quality,
+        options.optimize,
+        options.bounds(size)
+    );
+
+    if let Some((entry, data)) = cache::processed(&key) {
+        if let Some(&(_, mime)) = MIME_TYPES.iter().find(|(_, mime)| *mime == entry.mime) {
+            info!("Using cached {}", name);
+            return Ok(Image { mime, data });
+        }
+    }
+
+    let image = process(data, name, size, options)?;
+    if cache::hash(&image.data) != input {
+        cache::store_processed(&key, image.mime, &image.data);
+    }
+
+    Ok(image)
+}
+
+/// The source bytes are kept unless a target format is requested or the image exceeds the
+/// maximum size. Images that can't be transcoded, like vectors and animations, are kept too.
+fn process(data: Vec<u8>, name: &str, size: DisplaySize, options: &ImageOptions) -> Result<Image> {
+    let mime = mime_type(&data, name);
+
+    match mime {
+        Some(mime @ "image/svg+xml") => return Ok(Image { mime, data }),
+        Some(mime @ "image/gif") if is_animated_gif(&data) => {
+            if options.format.is_some()
+                || options.max_width.is_some()
+                || options.max_height.is_some()
+            {
+                info!("Keeping animated {}", name);
+            }
+            return Ok(Image { mime, data });
+        }
+        _ => (),
+    }
+
+    let bounds = options.bounds(size);
+    let oversized = match dimensions(&data) {
+        Some((width, height)) if width > bounds.0 || height > bounds.1 => {
+            info!(
+                "Resizing {} from {}x{} to fit {}x{}",
+                name,
+                width,
+                height,
+                size_label(bounds.0),
+                size_label(bounds.1)
+            );
+            true
+        }
+        _ => false,
+    };
+
+    let format = match (options.format, mime) {
+        (Some(format), Some(mime)) if mime == format.mime() && !oversized => None,
+        (Some(format), _) => Some(format),
+        (None, Some(mime)) if oversized => Some(ImageFormat::closest(mime)),
+        (None, Some(_)) => None,
+        // Formats browsers can't display, like TIFF
+        (None, None) => Some(ImageFormat::Png),
+    };
+
+    let image = match format {
+        None => Image {
+            mime: mime.expect("unknown formats are converted"),
+            data,
+        },
+        Some(format) => match transcode(&data, format, bounds, options.quality) {
+            Ok(transcoded) => transcoded,
+            Err(e) => {
+                warn!("can't convert {} to {:?}: {}", name.cyan(), format, e);
+                match mime {
+                    Some(mime) => Image { mime, data },
+                    None => return Err(e),
+                }
+            }
+        },
+    };
+
+    if options.optimize && image.mime == "image/png" {
+        info!("Optimizing image");
+        let data = oxipng::optimize_from_memory(&image.data, &oxipng::Options::default())?;
+        return Ok(Image { data, ..image });
+    }
+
+    Ok(image)
+}
+
+fn size_label(size: u32) -> String {
+    if size == u32::MAX {
+        "any".to_string()
+    } else {
+        size.to_string()
+    }
+}
+
+/// Width and height, read from the header without decoding the image.
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+fn is_animated_gif(data: &[u8]) -> bool {
+    GifDecoder::new(Cursor::new(data))
+        .map(|decoder| {
+            decoder
+                .into_frames()
+                .take_while(Result::is_ok)
+                .take(2)
+                .count()
+                > 1
+        })
+        .unwrap_or(false)
+}
+
+fn transcode(
+    data: &[u8],
+    format: ImageFormat,
+    (max_width, max_height): (u32, u32),
+    quality: u8,
+) -> Result<Image> {
+    info!("Converting to {:?}", format);
+
+    let mut img = image::load_from_memory(data)?;
+    if img.width() > max_width || img.height() > max_height {
+        img = img.resize(max_width, max_height, FilterType::Lanczos3);
+    }
+    let mut encoded: Vec<u8> = Vec::new();
+
+    match format {
+        ImageFormat::Png => {
+            img.write_to(&mut Cursor::new(&mut encoded), ImageOutputFormat::Png)?;
+        }
+        ImageFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb = DynamicImage::ImageRgb8(img.to_rgb8());
+            JpegEncoder::new_with_quality(&mut encoded, quality).encode_image(&rgb)?;
+        }
+        ImageFormat::Webp => {
+            let rgba = img.to_rgba8();
+            WebPEncoder::new_with_quality(&mut encoded, WebPQuality::lossy(quality)).encode(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                image::ColorType::Rgba8,
+            )?;
+        }
+    }
+
+    Ok(Image {
+        mime: format.mime(),
+        data: encoded,
+    })
+}
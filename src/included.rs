@@ -1,4 +1,6 @@
 use include_dir::{include_dir, Dir};
 
 pub static VENDOR_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/vendor");
+/// Libraries `build.rs` downloads for `--self-contained`.
+pub static LIB_DIR: Dir<'_> = include_dir!("$OUT_DIR/lib");
 pub static TEMPLATES_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/templates");
@@ -1,9 +1,11 @@
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{self, Read},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
+use anyhow::bail;
+
 pub fn read_stdin() -> io::Result<String> {
     let mut buffer = Vec::new();
     let mut stdin = io::stdin();
@@ -19,3 +21,22 @@ pub fn read_path(path: &PathBuf) -> io::Result<String> {
 
     Ok(buffer)
 }
+
+/// Reads a local file a document references.
+///
+/// With `confine`, the file must be inside that directory once `..` and symlinks are resolved,
+/// `--safe` documents can't read anything else.
+pub fn read_referenced(path: &Path, confine: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    if let Some(dir) = confine {
+        let inside = match (path.canonicalize(), dir.canonicalize()) {
+            (Ok(path), Ok(dir)) => path.starts_with(dir),
+            _ => false,
+        };
+
+        if !inside {
+            bail!("outside of {}, refused in safe mode", dir.display());
+        }
+    }
+
+    Ok(fs::read(path)?)
+}
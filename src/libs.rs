@@ -0,0 +1,139 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::document::RenderOptions;
+use crate::included::LIB_DIR;
+
+/// Third-party library that can be embedded into the output instead of loaded from a CDN.
+///
+/// Files are downloaded at build time by `build.rs`.
+pub struct Library {
+    pub name: &'static str,
+    js: &'static str,
+    css: Option<&'static str>,
+}
+
+pub const HIGHLIGHT: Library = Library {
+    name: "highlight.js",
+    js: "highlight/highlight.min.js",
+    css: Some("highlight/default.min.css"),
+};
+
+pub const KATEX: Library = Library {
+    name: "KaTeX",
+    js: "katex/katex.min.js",
+    css: Some("katex/katex.min.css"),
+};
+
+pub const MERMAID: Library = Library {
+    name: "Mermaid",
+    js: "mermaid/mermaid.min.js",
+    css: None,
+};
+
+#[derive(Serialize)]
+pub struct Bundle {
+    pub js: String,
+    pub css: Option<String>,
+}
+
+/// Libraries to inline into the page.
+#[derive(Serialize, Default)]
+pub struct Vendored {
+    pub highlight: Option<Bundle>,
+    pub math: Option<Bundle>,
+    pub diagrams: Option<Bundle>,
+}
+
+impl Vendored {
+    /// Fails when an enabled library is not vendored, the page would need the network otherwise.
+    pub fn load(options: &RenderOptions) -> Result<Self> {
+        let load = |enabled: bool, library: &Library| -> Result<Option<Bundle>> {
+            if !enabled {
+                return Ok(None);
+            }
+
+            library.bundle().map(Some).ok_or_else(|| {
+                anyhow!(
+                    "{} is not vendored in this build, build marky with network access to use --self-contained",
+                    library.name
+                )
+            })
+        };
+
+        Ok(Vendored {
+            highlight: load(options.client_highlight(), &HIGHLIGHT)?,
+            math: load(options.client_math(), &KATEX)?,
+            diagrams: load(options.diagrams, &MERMAID)?,
+        })
+    }
+}
+
+impl Library {
+    /// Reads the library from the downloaded files, `None` if any of its files is missing.
+    pub fn bundle(&self) -> Option<Bundle> {
+        let js = LIB_DIR.get_file(self.js)?.contents_utf8()?;
+
+        let css = match self.css {
+            Some(path) => {
+                let css = LIB_DIR.get_file(path)?.contents_utf8()?;
+                let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+
+                Some(inline_urls(css, dir))
+            }
+            None => None,
+        };
+
+        Some(Bundle {
+            // Inlined scripts must not close the surrounding <script> tag
+            js: js.replace("</script", "<\\/script"),
+            css,
+        })
+    }
+}
+
+/// Replaces `url(...)` references to downloaded files, such as fonts, with data URIs.
+fn inline_urls(css: &str, dir: &Path) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+
+    while let Some(start) = rest.find("url(") {
+        let (before, after) = rest.split_at(start + "url(".len());
+        result.push_str(before);
+
+        let end = match after.find(')') {
+            Some(end) => end,
+            None => {
+                rest = after;
+                break;
+            }
+        };
+
+        let url = after[..end].trim_matches(|c| c == '"' || c == '\'');
+
+        match LIB_DIR.get_file(dir.join(url)) {
+            Some(file) => result.push_str(&format!(
+                "data:{};base64,{}",
+                font_mime(url),
+                base64::encode(file.contents())
+            )),
+            None => result.push_str(&after[..end]),
+        }
+
+        rest = &after[end..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+fn font_mime(url: &str) -> &'static str {
+    match Path::new(url).extension().and_then(|ext| ext.to_str()) {
+        Some("woff2") => "font/woff2",
+        Some("woff") => "font/woff",
+        Some("ttf") => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
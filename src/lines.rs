@@ -0,0 +1,179 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use lol_html::{element, HtmlRewriter, Settings};
+use markdown::mdast::Node;
+
+/// Top-level node of the mdast and the tags it may be rendered as.
+struct Block {
+    tags: Vec<String>,
+    line: Option<usize>,
+    /// Raw HTML renders any number of elements, or none.
+    optional: bool,
+}
+
+impl Block {
+    fn new(tag: &str, node: &Node) -> Self {
+        Block {
+            tags: vec![tag.to_string()],
+            line: line(node),
+            optional: false,
+        }
+    }
+}
+
+fn line(node: &Node) -> Option<usize> {
+    node.position().map(|position| position.start.line)
+}
+
+fn blocks(root: &Node) -> Vec<Block> {
+    let children = match root.children() {
+        Some(children) => children,
+        None => return Vec::new(),
+    };
+
+    children
+        .iter()
+        .filter_map(|node| {
+            let block = match node {
+                Node::Heading(heading) => Block::new(&format!("h{}", heading.depth), node),
+                Node::Paragraph(_) => Block::new("p", node),
+                Node::Code(_) | Node::Math(_) => Block::new("pre", node),
+                Node::BlockQuote(_) => Block::new("blockquote", node),
+                Node::List(list) if list.ordered => Block::new("ol", node),
+                Node::List(_) => Block::new("ul", node),
+                Node::Table(_) => Block::new("table", node),
+                Node::ThematicBreak(_) => Block::new("hr", node),
+                Node::Html(html) => Block {
+                    tags: first_tag(&html.value).into_iter().collect(),
+                    line: line(node),
+                    optional: true,
+                },
+                _ => return None,
+            };
+
+            Some(block)
+        })
+        .collect()
+}
+
+/// Name of the element raw HTML starts with.
+fn first_tag(html: &str) -> Option<String> {
+    let name: String = html
+        .trim_start()
+        .strip_prefix('<')?
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect();
+
+    (!name.is_empty()).then(|| name.to_ascii_lowercase())
+}
+
+/// Lines of the nodes of a kind in document order, footnotes are rendered at the end.
+fn nested_lines(node: &Node, matches: fn(&Node) -> bool, lines: &mut Vec<Option<usize>>) {
+    if let Node::FootnoteDefinition(_) = node {
+        return;
+    }
+
+    if matches(node) {
+        lines.push(line(node));
+    }
+
+    if let Some(children) = node.children() {
+        for child in children {
+            nested_lines(child, matches, lines);
+        }
+    }
+}
+
+/// Adds `data-source-line` to the top-level blocks, list items and table rows.
+///
+/// Elements are matched in document order against the mdast, a block that can't be matched is
+/// skipped once before giving up on the element. Raw HTML can shift list items and rows.
+///
+/// Runs on the markup of markdown-rs, the later passes keep the attribute when they replace a
+/// block, like the table of contents or MathML.
+pub fn annotate(html: &str, root: &Node) -> anyhow::Result<String> {
+    let blocks = blocks(root);
+    let mut next = 0;
+
+    let mut items = Vec::new();
+    nested_lines(root, |node| matches!(node, Node::ListItem(_)), &mut items);
+    let mut items = items.into_iter();
+
+    let mut rows = Vec::new();
+    nested_lines(root, |node| matches!(node, Node::TableRow(_)), &mut rows);
+    let mut rows = rows.into_iter();
+
+    let depth = Rc::new(Cell::new(0usize));
+    let mut output = vec![];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                element!("*", |el| {
+                    let top_level = depth.get() == 0;
+
+                    if el.can_have_content() {
+                        depth.set(depth.get() + 1);
+
+                        let depth = depth.clone();
+                        el.on_end_tag(move |_| {
+                            depth.set(depth.get().saturating_sub(1));
+                            Ok(())
+                        })?;
+                    }
+
+                    if !top_level {
+                        return Ok(());
+                    }
+
+                    let tag = el.tag_name();
+                    let mut skipped = false;
+
+                    for (i, block) in blocks.iter().enumerate().skip(next) {
+                        if block.tags.contains(&tag) {
+                            next = i + 1;
+
+                            if let Some(line) = block.line {
+                                el.set_attribute("data-source-line", &line.to_string())?;
+                            }
+
+                            break;
+                        }
+
+                        if !block.optional {
+                            if skipped {
+                                break;
+                            }
+                            skipped = true;
+                        }
+                    }
+
+                    Ok(())
+                }),
+                element!("li:not([id^=\"user-content-fn-\"])", |el| {
+                    if let Some(line) = items.next().flatten() {
+                        el.set_attribute("data-source-line", &line.to_string())?;
+                    }
+
+                    Ok(())
+                }),
+                element!("tr", |el| {
+                    if let Some(line) = rows.next().flatten() {
+                        el.set_attribute("data-source-line", &line.to_string())?;
+                    }
+
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
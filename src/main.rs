@@ -1,16 +1,40 @@
 use clap::{CommandFactory, Parser};
 use colored::Colorize;
 use std::path::PathBuf;
+use std::time::Duration;
 
+mod alerts;
+mod anchors;
+mod assets;
+mod blocks;
+mod cache;
 mod cli;
 mod document;
+mod editor;
+mod fetch;
+mod frontmatter;
+mod highlight;
+mod html;
+mod images;
 mod included;
 mod ioutil;
+mod libs;
+mod lines;
 mod log;
+mod math;
 mod paths;
+mod pool;
+mod protocol;
+mod responsive;
+mod sanitize;
 mod server;
 mod service;
+mod site;
+mod slug;
 mod themes;
+mod toc;
+mod transclude;
+mod urls;
 mod watcher;
 
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
@@ -36,11 +60,17 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
-    if let Some(path) = &cli.path {
-        if path.is_dir() {
-            die!("Path is a directory")
-        }
+    if cli.clear_cache {
+        cache::clear()?;
+        info!(
+            "cleared {}",
+            paths::dirs::cache().display().to_string().cyan()
+        );
 
+        return Ok(());
+    }
+
+    if let Some(path) = &cli.path {
         if !path.exists() {
             die!("No such file")
         }
@@ -54,21 +84,89 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
         live: cli.live,
         include_images: cli.include_images,
         optimize_images: cli.optimize_images,
+        image_format: cli.image_format,
+        image_quality: cli.image_quality,
+        max_image_width: cli.max_image_width,
+        max_image_height: cli.max_image_height,
+        fetch: fetch::FetchPolicy {
+            connect_timeout: Duration::from_secs(cli.connect_timeout),
+            timeout: Duration::from_secs(cli.fetch_timeout),
+            max_bytes: cli.max_fetch_size,
+            allow_hosts: cli.allow_host.clone(),
+            deny_hosts: cli.deny_host.clone(),
+            offline: cli.offline,
+            headers: fetch::load_host_headers().unwrap_or_else(|e| {
+                warn!("can't load {}: {}", paths::files::hosts().display(), e);
+                Vec::new()
+            }),
+        },
+        assets: cli.assets,
+        responsive_images: cli.responsive_images,
+        image_widths: cli.image_widths.clone(),
+        safe: cli.safe.then(|| {
+            sanitize::Allowlist::load().unwrap_or_else(|e| {
+                warn!("can't load {}: {}", paths::files::sanitize().display(), e);
+                sanitize::Allowlist::default()
+            })
+        }),
+        permalinks: cli.permalinks,
+        self_contained: cli.self_contained,
+        highlighter: cli.highlighter,
+        highlight_scheme: cli.highlight_scheme,
+        math_renderer: cli.math_renderer,
     };
 
     info!("Using theme {}", options.theme.name.cyan());
-    if options.highlight {
+    if options.client_highlight() {
         info!("Highlight.js syntax highlighting is enabled");
+    } else if options.highlight {
+        info!("Server-side syntax highlighting is enabled");
     }
 
-    if options.math {
+    if options.client_math() {
         info!("KaTeX math rendering is enabled");
+    } else if options.math {
+        info!("MathML math rendering is enabled");
     }
 
     if options.diagrams {
         info!("Mermaid diagrams rendering is enabled");
     }
 
+    if options.safe.is_some() {
+        info!("Raw HTML is sanitized");
+    }
+
+    if options.assets == assets::AssetMode::Copy && (cli.stdout || cli.live) {
+        warn!("assets are only copied when writing to a file");
+    }
+
+    if options.responsive_images && (cli.stdout || cli.live) {
+        warn!("responsive images are only generated when writing to a file");
+    }
+
+    if let Some(dir) = cli.path.as_ref().filter(|path| path.is_dir()) {
+        if cli.watch || cli.live || cli.stdout {
+            die!("a directory can only be rendered into files");
+        }
+
+        let out_dir = cli.out.clone().unwrap_or_else(|| dir.clone());
+
+        let mut site = site::Site::scan(dir, &out_dir, &options)?;
+        if site.is_empty() {
+            die!("no markdown files in {}", dir.display());
+        }
+
+        site.build(&out_dir)?;
+        info!(
+            "wrote {} pages to {}",
+            site.len(),
+            out_dir.display().to_string().cyan()
+        );
+
+        return Ok(());
+    }
+
     let out = {
         let auto_extension = "html";
 
@@ -91,7 +189,8 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
         let path = &cli.path.unwrap();
 
         if cli.live {
-            watcher::watch_live(path, &options, cli.port).await?;
+            let editor = editor::template(cli.editor.as_deref());
+            watcher::watch_live(path, &options, cli.port, editor, cli.stdin_buffer).await?;
         } else {
             watcher::watch_file(path, &out, &options).await?;
         }
@@ -99,10 +198,19 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
-    let doc = document::Document {
-        text: cli.get_markdown()?,
-        options,
-    };
+    let text = transclude::expand(
+        &cli.get_markdown()?,
+        cli.path.as_deref(),
+        options.safe.is_some(),
+    )?
+    .text;
+    let mut doc = document::Document::new(text, options);
+    if let Some(path) = &cli.path {
+        doc.base_dir = urls::parent_dir(path);
+    }
+    if !cli.stdout {
+        doc.out_dir = Some(urls::parent_dir(&out));
+    }
     let buffer = doc.render()?;
 
     if cli.stdout {
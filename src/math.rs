@@ -0,0 +1,285 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use colored::Colorize;
+use latex2mathml::DisplayStyle;
+use lol_html::{element, html_content::ContentType, text, HtmlRewriter, Settings};
+
+use crate::{html, warn};
+
+/// `$$` math flow, ```` ```math ```` fences and `$` math text as rendered by markdown-rs.
+const MATH: &str = "code.language-math";
+
+/// Elements latex2mathml writes, anything else in its output is text.
+const MATHML_TAGS: [&str; 21] = [
+    "math",
+    "mi",
+    "mn",
+    "mo",
+    "mtext",
+    "mspace",
+    "msub",
+    "msup",
+    "msubsup",
+    "mover",
+    "munder",
+    "munderover",
+    "mroot",
+    "msqrt",
+    "mfrac",
+    "mrow",
+    "mtable",
+    "mtr",
+    "mtd",
+    "mstyle",
+    "mro",
+];
+
+/// Attributes latex2mathml writes, none of them takes a URL or a script.
+const MATHML_ATTRIBUTES: [&str; 12] = [
+    "xmlns",
+    "display",
+    "mathvariant",
+    "accent",
+    "stretchy",
+    "form",
+    "maxsize",
+    "minsize",
+    "width",
+    "displaystyle",
+    "columnalign",
+    "linethickness",
+];
+
+/// Math elements of a page in order, converted to MathML.
+struct Expressions {
+    mathml: Vec<Option<String>>,
+    /// Math element each `<pre>` wraps, by index.
+    blocks: HashMap<usize, usize>,
+}
+
+/// Replaces math blocks with MathML. Expressions that fail to convert are left as is.
+pub fn render_mathml(html_page: &str) -> anyhow::Result<String> {
+    let Expressions { mathml, blocks } = convert_expressions(html_page)?;
+
+    let mut output = vec![];
+    let pre_index = Cell::new(0usize);
+    let math_index = Cell::new(0usize);
+    let pre_attributes = RefCell::new(String::new());
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                // The MathML block replaces the <pre> around the code and keeps its attributes
+                element!("pre", |el| {
+                    let index = pre_index.get();
+                    pre_index.set(index + 1);
+
+                    let converted = blocks
+                        .get(&index)
+                        .map(|math| mathml[*math].is_some())
+                        .unwrap_or(false);
+
+                    if converted {
+                        *pre_attributes.borrow_mut() = el
+                            .attributes()
+                            .iter()
+                            .map(|attribute| {
+                                format!(
+                                    " {}=\"{}\"",
+                                    attribute.name(),
+                                    attribute.value().replace('"', "&quot;")
+                                )
+                            })
+                            .collect();
+
+                        el.remove_and_keep_content();
+                    }
+
+                    Ok(())
+                }),
+                element!(MATH, |el| {
+                    let index = math_index.get();
+                    math_index.set(index + 1);
+
+                    if let Some(Some(mathml)) = mathml.get(index) {
+                        let attributes = pre_attributes.take();
+                        let mathml = mathml.replacen("<math", &format!("<math{}", attributes), 1);
+                        el.replace(&mathml, ContentType::Html);
+                    }
+
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
+
+/// Converts the math elements of the page.
+///
+/// Elements are only replaced in a second pass, the `<pre>` is already written once its code
+/// is known.
+fn convert_expressions(html_page: &str) -> anyhow::Result<Expressions> {
+    let expressions = RefCell::new(Vec::new());
+    let blocks = RefCell::new(HashMap::new());
+
+    let pre_index = Cell::new(0usize);
+    let display = Cell::new(DisplayStyle::Inline);
+    let latex = RefCell::new(String::new());
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                element!("pre", |_| {
+                    pre_index.set(pre_index.get() + 1);
+                    Ok(())
+                }),
+                element!(&format!("pre > {}", MATH), |_| {
+                    blocks
+                        .borrow_mut()
+                        .insert(pre_index.get() - 1, expressions.borrow().len());
+                    Ok(())
+                }),
+                element!(MATH, |el| {
+                    let inline = el
+                        .get_attribute("class")
+                        .map(|class| class.split_whitespace().any(|class| class == "math-inline"))
+                        .unwrap_or(false);
+
+                    display.set(if inline {
+                        DisplayStyle::Inline
+                    } else {
+                        DisplayStyle::Block
+                    });
+                    latex.borrow_mut().clear();
+
+                    // Empty elements have no text to convert
+                    expressions.borrow_mut().push(None);
+
+                    Ok(())
+                }),
+                text!(MATH, |chunk| {
+                    latex.borrow_mut().push_str(chunk.as_str());
+
+                    if chunk.last_in_text_node() {
+                        let mathml = convert(&html::unescape(&latex.borrow()), display.get());
+                        *expressions
+                            .borrow_mut()
+                            .last_mut()
+                            .expect("element was seen") = mathml;
+                    }
+
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(Expressions {
+        mathml: expressions.into_inner(),
+        blocks: blocks.into_inner(),
+    })
+}
+
+fn convert(latex: &str, display: DisplayStyle) -> Option<String> {
+    let latex = latex.trim();
+
+    match latex2mathml::latex_to_mathml(latex, display) {
+        // Unsupported commands are reported inside the MathML instead of failing
+        Ok(mathml) if mathml.contains("[PARSE ERROR") => {
+            warn!(
+                "can't convert {} to MathML: unsupported syntax",
+                latex.cyan()
+            );
+            None
+        }
+        Ok(mathml) => Some(escape_text(&mathml)),
+        Err(e) => {
+            warn!("can't convert {} to MathML: {}", latex.cyan(), e);
+            None
+        }
+    }
+}
+
+/// Escapes the text latex2mathml writes verbatim between its elements, like `<mo><</mo>` or
+/// `<mtext>a & b</mtext>`, so that only its own elements and attributes remain markup.
+fn escape_text(mathml: &str) -> String {
+    let mut escaped = String::with_capacity(mathml.len());
+    let mut rest = mathml;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some(len) = tag_len(rest) {
+                escaped.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
+            }
+        }
+
+        if c == '&' {
+            if let Some(len) = char_reference_len(rest) {
+                escaped.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
+            }
+        }
+
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            c => escaped.push(c),
+        }
+
+        rest = &rest[c.len_utf8()..];
+    }
+
+    escaped
+}
+
+/// Length of a MathML tag at the start of the text, like `<mo stretchy="true">` or `</mi>`.
+fn tag_len(text: &str) -> Option<usize> {
+    let inner_start = if text.starts_with("</") { 2 } else { 1 };
+    let end = text.find('>')?;
+    let inner = text[inner_start..end].trim_end_matches('/');
+
+    let mut parts = inner.split(' ');
+    let name = parts.next()?;
+    if !MATHML_TAGS.contains(&name) {
+        return None;
+    }
+
+    for attribute in parts {
+        let (name, value) = attribute.split_once('=')?;
+        let value = match value.strip_prefix('"') {
+            Some(value) => value.strip_suffix('"')?,
+            None => value,
+        };
+
+        if !MATHML_ATTRIBUTES.contains(&name) || value.contains(['"', '<', '&']) {
+            return None;
+        }
+    }
+
+    Some(end + 1)
+}
+
+/// Length of a numeric character reference at the start of the text, like `&#x2061;`.
+fn char_reference_len(text: &str) -> Option<usize> {
+    let digits = text.strip_prefix("&#x")?;
+    let end = digits.find(';')?;
+
+    (end > 0 && digits[..end].chars().all(|c| c.is_ascii_hexdigit())).then(|| 3 + end + 1)
+}
@@ -8,6 +8,12 @@ pub mod dirs {
 
         config_dir.join("marky")
     }
+
+    pub fn cache() -> PathBuf {
+        let cache_dir = dirs::cache_dir().unwrap_or(".".into());
+
+        cache_dir.join("marky")
+    }
 }
 
 pub mod files {
@@ -16,4 +22,12 @@ pub mod files {
     pub fn themes() -> PathBuf {
         super::dirs::config().join("themes.toml")
     }
+
+    pub fn hosts() -> PathBuf {
+        super::dirs::config().join("hosts.toml")
+    }
+
+    pub fn sanitize() -> PathBuf {
+        super::dirs::config().join("sanitize.toml")
+    }
 }
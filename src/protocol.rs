@@ -0,0 +1,104 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::blocks::Block;
+
+/// Version of the websocket messages, `script.js` reloads the page when the server speaks
+/// another one. Bump it on incompatible changes and keep `PROTOCOL_VERSION` there in sync.
+pub const VERSION: u32 = 1;
+
+/// Messages from the live server to the preview.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage<'a> {
+    /// Blocks of the body, the preview keeps the ones with an unchanged key.
+    Render {
+        blocks: &'a [Block],
+    },
+    /// The document can't be rendered, the preview keeps the last body.
+    Error {
+        message: &'a str,
+    },
+    /// Scroll to the block of a source line.
+    Scroll {
+        line: usize,
+    },
+    Theme {
+        css: &'a str,
+        highlight_css: Option<&'a str>,
+    },
+    Title {
+        title: &'a str,
+    },
+    /// The page loads other scripts or styles, only a reload picks them up.
+    Reload,
+}
+
+/// Messages from the preview to the live server.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    /// Open the source in the editor at a line.
+    Edit { line: usize },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u32,
+    #[serde(flatten)]
+    message: &'a ServerMessage<'a>,
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+pub fn encode(message: &ServerMessage) -> String {
+    serde_json::to_string(&Envelope {
+        version: VERSION,
+        message,
+    })
+    .expect("messages always serialize")
+}
+
+pub fn decode(text: &str) -> Result<ClientMessage> {
+    let Versioned { version } = serde_json::from_str(text)?;
+    if version != VERSION {
+        bail!("protocol version {} is not supported", version);
+    }
+
+    Ok(serde_json::from_str(text)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_edit() {
+        let message = decode(r#"{"version": 1, "type": "edit", "line": 42}"#).unwrap();
+
+        assert!(matches!(message, ClientMessage::Edit { line: 42 }));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let error = decode(r#"{"version": 2, "type": "edit", "line": 42}"#).unwrap_err();
+
+        assert!(error.to_string().contains("version 2"));
+        assert!(decode(r#"{"type": "edit", "line": 42}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_messages() {
+        assert!(decode(r#"{"version": 1, "type": "delete"}"#).is_err());
+    }
+
+    #[test]
+    fn encodes_version_and_type() {
+        let message = encode(&ServerMessage::Scroll { line: 3 });
+
+        assert_eq!(message, r#"{"version":1,"type":"scroll","line":3}"#);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+use colored::Colorize;
+use lol_html::{element, HtmlRewriter, Settings};
+
+use crate::images::{self, DisplaySize, ImageOptions};
+use crate::{assets, info, ioutil, pool, sanitize, urls, warn};
+
+/// Raster formats worth resizing, GIFs may be animated.
+const RESIZABLE: [&str; 3] = ["image/png", "image/jpeg", "image/webp"];
+
+/// Resized copies of an image.
+struct Variants {
+    width: u32,
+    height: u32,
+    /// Not set when the image is smaller than every variant
+    srcset: Option<String>,
+}
+
+/// Writes resized variants of local images into the assets directory of `out_dir` and points
+/// `srcset` at them. Every image is loaded lazily and gets explicit dimensions.
+///
+/// The references in the page must be relative to `out_dir` already. Images outside of
+/// `confine` are never read.
+pub fn responsive_images(
+    html_page: &str,
+    out_dir: &Path,
+    widths: &[u32],
+    options: &ImageOptions,
+    confine: Option<&Path>,
+) -> Result<String> {
+    let mut sources: Vec<String> = Vec::new();
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!("img[src]:not([srcset])", |el| {
+                let src = el.get_attribute("src").expect("src was required");
+
+                if urls::is_relative_local(&src) && !sources.contains(&src) {
+                    sources.push(src);
+                }
+
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |_: &[u8]| (),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    let generated = pool::map(&sources, pool::cpus(), |src| {
+        variants(src, out_dir, widths, options, confine)
+    });
+
+    let mut variants = HashMap::new();
+    for (src, generated) in sources.iter().zip(generated) {
+        match generated {
+            Ok(Some(generated)) => {
+                variants.insert(src, generated);
+            }
+            Ok(None) => (),
+            Err(e) => warn!("can't resize {}: {}", src.cyan(), e),
+        }
+    }
+
+    let mut output = vec![];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!("img[src]", |el| {
+                if !el.has_attribute("loading") {
+                    el.set_attribute("loading", "lazy")?;
+                }
+                if !el.has_attribute("decoding") {
+                    el.set_attribute("decoding", "async")?;
+                }
+
+                let src = el.get_attribute("src").expect("src was required");
+                let Some(variants) = variants.get(&src) else {
+                    return Ok(());
+                };
+
+                if el.has_attribute("srcset") {
+                    return Ok(());
+                }
+
+                let size =
+                    DisplaySize::parse(el.get_attribute("width"), el.get_attribute("height"));
+
+                // Keep the aspect ratio when only one dimension is given
+                let width = match (size.width, size.height) {
+                    (Some(width), _) => Some(width),
+                    (None, Some(height)) => Some(
+                        (height as u64 * variants.width as u64 / variants.height as u64) as u32,
+                    ),
+                    (None, None) => None,
+                };
+
+                if let Some(srcset) = &variants.srcset {
+                    el.set_attribute("srcset", srcset)?;
+
+                    // Values like `50%` aren't a pixel width, the image then fills the viewport
+                    let sizes = match width {
+                        Some(width) => format!("(max-width: {width}px) 100vw, {width}px"),
+                        None if el.has_attribute("width") => "100vw".to_string(),
+                        None => format!("(max-width: {0}px) 100vw, {0}px", variants.width),
+                    };
+                    el.set_attribute("sizes", &sizes)?;
+                }
+
+                // Reserve space for the image, unless the page sets its size already
+                if !el.has_attribute("width") && !el.has_attribute("height") {
+                    el.set_attribute("width", &variants.width.to_string())?;
+                    el.set_attribute("height", &variants.height.to_string())?;
+                }
+
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
+
+fn variants(
+    src: &str,
+    out_dir: &Path,
+    widths: &[u32],
+    options: &ImageOptions,
+    confine: Option<&Path>,
+) -> Result<Option<Variants>> {
+    let path = urls::normalize(&urls::resolve(out_dir, &sanitize::decode_entities(src)));
+    let data = ioutil::read_referenced(&path, confine)?;
+
+    match images::mime_type(&data, src) {
+        Some(mime) if RESIZABLE.contains(&mime) => (),
+        _ => return Ok(None),
+    }
+
+    let Some((width, height)) = images::dimensions(&data) else {
+        return Ok(None);
+    };
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut candidates = Vec::new();
+    for &variant_width in widths
+        .iter()
+        .filter(|&&variant_width| variant_width < width)
+    {
+        let variant = images::prepare(
+            data.clone(),
+            src,
+            DisplaySize::default(),
+            &ImageOptions {
+                max_width: Some(variant_width),
+                max_height: None,
+                ..*options
+            },
+        )?;
+
+        let url = assets::write_asset(
+            &variant.data,
+            &format!("{}-{}w", stem, variant_width),
+            Some(variant.extension()),
+            out_dir,
+        )?;
+
+        candidates.push(format!("{} {}w", url, variant_width));
+    }
+
+    if candidates.is_empty() {
+        return Ok(Some(Variants {
+            width,
+            height,
+            srcset: None,
+        }));
+    }
+
+    info!("Resized {} to {} variants", src, candidates.len());
+    candidates.push(format!("{} {}w", src, width));
+
+    Ok(Some(Variants {
+        width,
+        height,
+        srcset: Some(candidates.join(", ")),
+    }))
+}
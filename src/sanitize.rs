@@ -0,0 +1,363 @@
+use std::fs;
+
+use anyhow::Result;
+use lol_html::{element, HtmlRewriter, Settings};
+use serde::Deserialize;
+
+use crate::paths;
+use crate::urls;
+
+/// Removed together with their content, unwrapping them could turn text into markup.
+const DROPPED_TAGS: &[&str] = &[
+    "applet",
+    "embed",
+    "frame",
+    "frameset",
+    "iframe",
+    "math",
+    "noembed",
+    "noframes",
+    "noscript",
+    "object",
+    "plaintext",
+    "script",
+    "select",
+    "style",
+    "svg",
+    "template",
+    "textarea",
+    "title",
+    "xmp",
+];
+
+/// Attributes holding a URL, or a list of them in case of `srcset`.
+const URL_ATTRIBUTES: &[&str] = &[
+    "action",
+    "background",
+    "cite",
+    "data",
+    "formaction",
+    "href",
+    "longdesc",
+    "ping",
+    "poster",
+    "src",
+    "srcset",
+    "xlink:href",
+];
+
+/// Elements whose URLs may be `data:image/` ones.
+const IMAGE_TAGS: &[&str] = &["img", "picture", "source", "video"];
+
+/// What raw HTML may contain in safe mode.
+///
+/// Read from `sanitize.toml` in the config directory, every list given there replaces the
+/// default one. Attribute names ending with `*` match any suffix, like `aria-*`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Allowlist {
+    pub tags: Vec<String>,
+    pub attributes: Vec<String>,
+    pub schemes: Vec<String>,
+}
+
+impl Default for Allowlist {
+    fn default() -> Self {
+        let strings = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+
+        Allowlist {
+            tags: strings(&[
+                "a",
+                "abbr",
+                "b",
+                "bdi",
+                "bdo",
+                "blockquote",
+                "br",
+                "caption",
+                "cite",
+                "code",
+                "col",
+                "colgroup",
+                "dd",
+                "del",
+                "details",
+                "dfn",
+                "div",
+                "dl",
+                "dt",
+                "em",
+                "figcaption",
+                "figure",
+                "h1",
+                "h2",
+                "h3",
+                "h4",
+                "h5",
+                "h6",
+                "hr",
+                "i",
+                "img",
+                "input",
+                "ins",
+                "kbd",
+                "li",
+                "mark",
+                "ol",
+                "p",
+                "picture",
+                "pre",
+                "q",
+                "rp",
+                "rt",
+                "ruby",
+                "s",
+                "samp",
+                "section",
+                "small",
+                "source",
+                "span",
+                "strike",
+                "strong",
+                "sub",
+                "summary",
+                "sup",
+                "table",
+                "tbody",
+                "td",
+                "tfoot",
+                "th",
+                "thead",
+                "time",
+                "tr",
+                "tt",
+                "u",
+                "ul",
+                "var",
+                "wbr",
+            ]),
+            attributes: strings(&[
+                "align",
+                "alt",
+                "aria-*",
+                "checked",
+                "class",
+                "colspan",
+                "data-footnote*",
+                "datetime",
+                "dir",
+                "disabled",
+                "height",
+                "href",
+                "id",
+                "lang",
+                "media",
+                "open",
+                "rowspan",
+                "sizes",
+                "span",
+                "src",
+                "srcset",
+                "start",
+                "title",
+                "type",
+                "width",
+            ]),
+            schemes: strings(&["http", "https", "mailto"]),
+        }
+    }
+}
+
+impl Allowlist {
+    /// Reads the allowlist from the config directory, the defaults are used without one.
+    pub fn load() -> Result<Self> {
+        let path = paths::files::sanitize();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(toml::from_str(&fs::read_to_string(&path)?)?)
+    }
+
+    fn allows_tag(&self, tag: &str) -> bool {
+        self.tags
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(tag))
+    }
+
+    fn allows_attribute(&self, name: &str) -> bool {
+        // Event handlers can't be allowed
+        if name.starts_with("on") {
+            return false;
+        }
+
+        self.attributes
+            .iter()
+            .any(|allowed| match allowed.strip_suffix('*') {
+                Some(prefix) => name.starts_with(&prefix.to_ascii_lowercase()),
+                None => allowed.eq_ignore_ascii_case(name),
+            })
+    }
+
+    /// Whether a decoded URL is relative or has an allowed scheme.
+    fn allows_url(&self, url: &str, tag: &str) -> bool {
+        // Browsers ignore whitespace and control characters anywhere in a scheme
+        let url: String = url.chars().filter(|c| *c > ' ').collect();
+
+        let scheme = match url.find(':') {
+            Some(end) if !url[..end].contains(['/', '?', '#']) => &url[..end],
+            _ => return true,
+        };
+
+        if scheme.eq_ignore_ascii_case("data") && IMAGE_TAGS.contains(&tag) {
+            return url[scheme.len() + 1..]
+                .to_ascii_lowercase()
+                .starts_with("image/");
+        }
+
+        self.schemes
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+    }
+}
+
+/// Removes the tags, attributes and URLs of raw HTML that the allowlist doesn't cover.
+///
+/// Unknown elements are unwrapped, keeping their content. URL attributes are written back
+/// decoded, so that the browser sees exactly the URL that was checked.
+pub fn sanitize(html: &str, allowlist: &Allowlist) -> Result<String> {
+    let mut output = vec![];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!("*", |el| {
+                let tag = el.tag_name();
+
+                if !allowlist.allows_tag(&tag) {
+                    if DROPPED_TAGS.contains(&tag.as_str()) {
+                        el.remove();
+                    } else {
+                        el.remove_and_keep_content();
+                    }
+
+                    return Ok(());
+                }
+
+                let attributes: Vec<(String, String)> = el
+                    .attributes()
+                    .iter()
+                    .map(|attribute| (attribute.name().to_ascii_lowercase(), attribute.value()))
+                    .collect();
+
+                for (name, value) in attributes {
+                    if !allowlist.allows_attribute(&name) {
+                        el.remove_attribute(&name);
+                        continue;
+                    }
+
+                    if !URL_ATTRIBUTES.contains(&name.as_str()) {
+                        continue;
+                    }
+
+                    let value = decode_entities(&value);
+
+                    let value = if name == "srcset" {
+                        urls::srcset_candidates(&value)
+                            .into_iter()
+                            .filter(|(url, _)| allowlist.allows_url(url, &tag))
+                            .map(|(url, descriptor)| {
+                                format!("{} {}", url, descriptor).trim_end().to_string()
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    } else if allowlist.allows_url(&value, &tag) {
+                        value
+                    } else {
+                        el.remove_attribute(&name);
+                        continue;
+                    };
+
+                    el.set_attribute(&name, &value.replace('&', "&amp;"))?;
+                }
+
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
+
+/// Named character references that can hide a scheme, like `javascript&colon;`.
+const ENTITIES: &[(&str, char)] = &[
+    ("amp", '&'),
+    ("apos", '\''),
+    ("colon", ':'),
+    ("gt", '>'),
+    ("lt", '<'),
+    ("newline", '\n'),
+    ("quot", '"'),
+    ("sol", '/'),
+    ("tab", '\t'),
+];
+
+/// Decodes numeric and common named character references of an attribute value.
+///
+/// Unknown references are kept, they end up escaped and so are never decoded by the browser.
+pub fn decode_entities(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (character, length) = if let Some(number) = rest.strip_prefix('#') {
+            let (digits, radix, prefix) = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => (hex, 16, 2),
+                None => (number, 10, 1),
+            };
+
+            let count = digits.chars().take_while(|c| c.is_digit(radix)).count();
+
+            if count == 0 {
+                (None, 0)
+            } else {
+                let character = u32::from_str_radix(&digits[..count], radix)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .filter(|c| *c != '\0')
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+
+                (Some(character), prefix + count)
+            }
+        } else {
+            ENTITIES
+                .iter()
+                .find(|(name, _)| {
+                    rest.get(..name.len())
+                        .map(|prefix| prefix.eq_ignore_ascii_case(name))
+                        .unwrap_or(false)
+                })
+                .map(|(name, character)| (Some(*character), name.len()))
+                .unwrap_or((None, 0))
+        };
+
+        match character {
+            Some(character) => {
+                decoded.push(character);
+                rest = &rest[length..];
+                rest = rest.strip_prefix(';').unwrap_or(rest);
+            }
+            None => decoded.push('&'),
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
@@ -1,8 +1,16 @@
-use std::{cell::RefCell, net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
-use crate::document;
+use anyhow::Result;
+
+use crate::blocks::{self, Block};
+use crate::document::{self, Document};
+use crate::protocol::ServerMessage;
+use crate::watcher::Change;
 use crate::{info, service, warn};
-use axum::{routing::get, Extension, Router};
+use axum::{
+    routing::{get, post},
+    Extension, Router,
+};
 use colored::Colorize;
 use tokio::sync::{
     oneshot,
@@ -13,42 +21,170 @@ use tokio::sync::{
 pub struct Config {
     pub root_dir: PathBuf,
     pub render_options: document::RenderOptions,
+    /// Markdown file of the preview.
+    pub source: PathBuf,
+    /// Command template opening the source at a line, see `editor::open`.
+    pub editor: Option<String>,
+    /// Receives the buffers posted to `/buffer`.
+    pub changes: std::sync::mpsc::Sender<Change>,
+}
+
+/// What the preview shows, every websocket sends the parts that changed since its last one.
+#[derive(Clone, Default, Debug)]
+pub struct Preview {
+    pub blocks: Vec<Block>,
+    pub title: String,
+    pub theme: String,
+    pub highlight_css: Option<String>,
+    pub head: Head,
+    pub error: Option<String>,
+    /// Whole page, served to browsers opening the preview.
+    pub page: String,
 }
 
-/// Code is taken from the https://github.com/euclio/aurelius/
+/// Scripts and styles the head of the page loads.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Head {
+    highlight: bool,
+    math: bool,
+    diagrams: bool,
+    highlight_css: bool,
+}
+
+impl Preview {
+    fn new(document: &Document) -> Result<Self> {
+        let body = document.render_body();
+
+        // The preview then replaces the whole document
+        let blocks = blocks::split(&body).unwrap_or_else(|e| {
+            warn!("can't split the document into blocks: {}", e);
+            vec![Block {
+                key: String::new(),
+                html: body.clone(),
+                lines: Vec::new(),
+            }]
+        });
+
+        let highlight_css = document.highlight_css()?;
+
+        Ok(Preview {
+            blocks,
+            title: document.title().unwrap_or("Document".into()),
+            theme: document.options.theme.resolve()?,
+            head: Head {
+                highlight: document.options.client_highlight(),
+                math: document.options.client_math(),
+                diagrams: document.options.diagrams,
+                highlight_css: highlight_css.is_some(),
+            },
+            highlight_css,
+            error: None,
+            page: String::from_utf8(document.render_page(body)?)?,
+        })
+    }
+
+    /// Messages updating a preview that shows `last`, everything for a new one.
+    ///
+    /// Nothing rendered yet has no title or theme, the waiting page keeps its own.
+    pub fn messages_since(&self, last: Option<&Preview>) -> Vec<ServerMessage<'_>> {
+        let mut messages = Vec::new();
+
+        if let Some(last) = last {
+            if last.head != self.head {
+                return vec![ServerMessage::Reload];
+            }
+        }
+
+        let rendered = !self.page.is_empty();
+
+        if rendered && last.map(|last| last.title != self.title).unwrap_or(true) {
+            messages.push(ServerMessage::Title { title: &self.title });
+        }
+
+        if rendered
+            && last
+                .map(|last| last.theme != self.theme || last.highlight_css != self.highlight_css)
+                .unwrap_or(true)
+        {
+            messages.push(ServerMessage::Theme {
+                css: &self.theme,
+                highlight_css: self.highlight_css.as_deref(),
+            });
+        }
+
+        // Moved lines keep the keys but still need to reach the client
+        let keys = |preview: &Preview| -> Vec<(String, Vec<usize>)> {
+            preview
+                .blocks
+                .iter()
+                .map(|block| (block.key.clone(), block.lines.clone()))
+                .collect()
+        };
+
+        // Rendering also clears a previous error
+        let changed = match last {
+            Some(last) => {
+                keys(last) != keys(self) || (last.error.is_some() && self.error.is_none())
+            }
+            None => rendered,
+        };
+
+        if changed {
+            messages.push(ServerMessage::Render {
+                blocks: &self.blocks,
+            });
+        }
+
+        if let Some(message) = &self.error {
+            if last.map(|last| last.error != self.error).unwrap_or(true) {
+                messages.push(ServerMessage::Error { message });
+            }
+        }
+
+        messages
+    }
+}
+
+// Code is taken from the https://github.com/euclio/aurelius/
 
 /// Markdown preview server.
 ///
 /// Listens for HTTP connections and serves a page containing a live markdown preview. The page
 /// contains JavaScript to open a websocket connection back to the server for rendering updates.
+///
+/// Editors can `POST` the line of their cursor to `/scroll` to make the preview follow it, and
+/// their unsaved buffer to `/buffer`.
 #[derive(Debug)]
 pub struct Server {
     pub addr: SocketAddr,
-    output: RefCell<String>,
-    tx: Sender<String>,
+    tx: Sender<Arc<Preview>>,
     _shutdown_tx: oneshot::Sender<()>,
 }
 
 impl Server {
-    pub fn bind(addr: &SocketAddr, config: Config) -> Self {
-        let (tx, rx) = watch::channel(String::new());
+    pub fn bind(addr: &SocketAddr, config: Config) -> Result<Self> {
+        // The address is known before the routes, requests are checked against it
+        let listener = std::net::TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+
+        let (tx, rx) = watch::channel(Arc::new(Preview::default()));
+        let (line_tx, line_rx) = watch::channel(0usize);
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
         let app = Router::new()
             .route("/", get(service::websocket_handler))
+            .route("/scroll", post(service::scroll_handler))
+            .route("/buffer", post(service::buffer_handler))
             .fallback_service(get(service::serve_static_file))
             .layer(Extension(rx))
+            .layer(Extension(line_rx))
+            .layer(Extension(Arc::new(line_tx)))
+            .layer(Extension(addr))
             .layer(Extension(config));
 
-        let http_server = axum::Server::bind(addr).serve(app.into_make_service());
-        let addr = http_server.local_addr();
+        let http_server = axum::Server::from_tcp(listener)?.serve(app.into_make_service());
 
         info!("Listening on {}", addr);
-        info!("Opening in browser");
-
-        if let Err(e) = open::that(format!("http://{}", addr)) {
-            warn!("Failed to open the page: {}", e);
-        }
 
         let http_server = http_server.with_graceful_shutdown(async move {
             let _ = shutdown_rx.await;
@@ -56,17 +192,29 @@ impl Server {
 
         tokio::spawn(http_server);
 
-        Server {
+        Ok(Server {
             addr,
-            output: RefCell::new(String::new()),
             tx,
             _shutdown_tx: shutdown_tx,
-        }
+        })
     }
 
-    pub async fn send(&self, document: &crate::document::Document) {
-        self.output
-            .replace(self.tx.send_replace(document.render_body()));
+    /// Updates the previews, an error is shown over the last rendered document.
+    pub async fn send(&self, document: &Result<Document>) {
+        let preview = document
+            .as_ref()
+            .map_err(|e| e.to_string())
+            .and_then(|document| Preview::new(document).map_err(|e| e.to_string()));
+
+        let preview = match preview {
+            Ok(preview) => preview,
+            Err(message) => Preview {
+                error: Some(message),
+                ..(**self.tx.borrow()).clone()
+            },
+        };
+
+        self.tx.send_replace(Arc::new(preview));
     }
 
     // TODO: use it
@@ -74,3 +222,106 @@ impl Server {
         self._shutdown_tx.send(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preview(keys: &[&str]) -> Preview {
+        Preview {
+            blocks: keys
+                .iter()
+                .map(|key| Block {
+                    key: key.to_string(),
+                    html: format!("<p>{}</p>", key),
+                    lines: Vec::new(),
+                })
+                .collect(),
+            title: "Title".into(),
+            theme: "body {}".into(),
+            page: "<html></html>".into(),
+            ..Preview::default()
+        }
+    }
+
+    fn types(messages: &[ServerMessage]) -> Vec<&'static str> {
+        messages
+            .iter()
+            .map(|message| match message {
+                ServerMessage::Render { .. } => "render",
+                ServerMessage::Error { .. } => "error",
+                ServerMessage::Scroll { .. } => "scroll",
+                ServerMessage::Theme { .. } => "theme",
+                ServerMessage::Title { .. } => "title",
+                ServerMessage::Reload => "reload",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn new_preview_gets_everything() {
+        let current = preview(&["a", "b"]);
+
+        assert_eq!(
+            types(&current.messages_since(None)),
+            ["title", "theme", "render"]
+        );
+    }
+
+    #[test]
+    fn unchanged_preview_sends_nothing() {
+        let current = preview(&["a", "b"]);
+
+        assert!(current.messages_since(Some(&current.clone())).is_empty());
+    }
+
+    #[test]
+    fn changed_blocks_render() {
+        let last = preview(&["a", "b"]);
+        let current = preview(&["a", "c"]);
+
+        assert_eq!(types(&current.messages_since(Some(&last))), ["render"]);
+    }
+
+    #[test]
+    fn changed_head_reloads() {
+        let last = preview(&["a"]);
+        let current = Preview {
+            head: Head {
+                math: true,
+                ..Head::default()
+            },
+            title: "Other".into(),
+            ..preview(&["b"])
+        };
+
+        assert_eq!(types(&current.messages_since(Some(&last))), ["reload"]);
+    }
+
+    #[test]
+    fn error_keeps_blocks_and_clears_on_render() {
+        let last = preview(&["a"]);
+        let failed = Preview {
+            error: Some("oops".into()),
+            ..last.clone()
+        };
+
+        assert_eq!(types(&failed.messages_since(Some(&last))), ["error"]);
+        assert!(failed.messages_since(Some(&failed.clone())).is_empty());
+        assert_eq!(types(&last.messages_since(Some(&failed))), ["render"]);
+    }
+
+    #[test]
+    fn failed_first_render_keeps_waiting_page() {
+        let failed = Preview {
+            error: Some("oops".into()),
+            ..Preview::default()
+        };
+
+        assert_eq!(types(&failed.messages_since(None)), ["error"]);
+        assert_eq!(
+            types(&preview(&["a"]).messages_since(Some(&failed))),
+            ["title", "theme", "render"]
+        );
+    }
+}
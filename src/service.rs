@@ -4,49 +4,247 @@ use axum::{
         ws::{Message as AxumMessage, WebSocket, WebSocketUpgrade},
         Extension,
     },
-    http::{Request, StatusCode},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
     response::{Html, IntoResponse},
 };
-use tokio::sync::watch::Receiver;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::watch::{Receiver, Sender};
 use tower::util::ServiceExt;
 use tower_http::services::ServeDir;
 
+use crate::editor;
+use crate::protocol::{self, ClientMessage, ServerMessage};
+use crate::server::Preview;
+use crate::watcher::{BufferMessage, Change};
+use crate::{info, warn};
+use colored::Colorize;
+
 pub async fn websocket_handler(
     ws: Option<WebSocketUpgrade>,
+    headers: HeaderMap,
+    Extension(addr): Extension<SocketAddr>,
     Extension(config): Extension<crate::server::Config>,
-    Extension(html_rx): Extension<Receiver<String>>,
+    Extension(preview_rx): Extension<Receiver<Arc<Preview>>>,
+    Extension(line_rx): Extension<Receiver<usize>>,
 ) -> impl IntoResponse {
+    // Other pages open in the browser must not read the preview or drive the editor
+    if !same_origin(&headers, &addr) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
     if let Some(ws) = ws {
-        return ws.on_upgrade(|ws| async { handle_websocket(ws, html_rx).await });
+        return ws
+            .on_upgrade(|ws| async { handle_websocket(ws, config, preview_rx, line_rx).await });
     }
 
-    let doc = crate::document::Document {
-        text: "😴 Waiting for changes".into(),
-        options: config.render_options,
-    };
+    let page = preview_rx.borrow().page.clone();
+    if !page.is_empty() {
+        return (StatusCode::OK, Html(page)).into_response();
+    }
+
+    let doc =
+        crate::document::Document::new("😴 Waiting for changes".into(), config.render_options);
 
     let buffer = doc.render().expect("Document with empty text must render");
     let html = String::from_utf8(buffer).expect("Must be a valid utf8");
     (StatusCode::OK, Html(html)).into_response()
 }
 
-async fn handle_websocket(mut socket: WebSocket, mut html_rx: Receiver<String>) {
-    while html_rx.changed().await.is_ok() {
-        let html = html_rx.borrow().clone();
-        socket.send(AxumMessage::Text(html)).await.unwrap();
+/// Whether a request comes from the preview itself, other clients like editors send no origin.
+///
+/// Both the host and the origin are compared with the bound address, a site rebinding its own
+/// domain to 127.0.0.1 controls them otherwise.
+fn same_origin(headers: &HeaderMap, addr: &SocketAddr) -> bool {
+    let hosts = [addr.to_string(), format!("localhost:{}", addr.port())];
+    let local = |value: &HeaderValue, scheme: &str| {
+        value
+            .to_str()
+            .map(|value| {
+                hosts
+                    .iter()
+                    .any(|host| value == format!("{}{}", scheme, host))
+            })
+            .unwrap_or(false)
+    };
+
+    let host = headers.get(header::HOST).map(|host| local(host, ""));
+    let origin = headers
+        .get(header::ORIGIN)
+        .map(|origin| local(origin, "http://"));
+
+    host.unwrap_or(true) && origin.unwrap_or(true)
+}
+
+/// Opens the source at the line of the block double-clicked in the preview.
+fn handle_message(message: &str, config: &crate::server::Config) {
+    let line = match protocol::decode(message) {
+        Ok(ClientMessage::Edit { line }) => line,
+        Err(e) => {
+            warn!("invalid message from the preview: {}", e);
+            return;
+        }
+    };
+
+    let editor = match &config.editor {
+        Some(editor) => editor,
+        None => {
+            warn!("set {} or $EDITOR to open the source", "--editor".yellow());
+            return;
+        }
+    };
+
+    info!("Opening {} at line {}", config.source.display(), line);
+    if let Err(e) = editor::open(editor, &config.source, line) {
+        warn!("{}", e);
+    }
+}
+
+async fn handle_websocket(
+    mut socket: WebSocket,
+    config: crate::server::Config,
+    mut preview_rx: Receiver<Arc<Preview>>,
+    mut line_rx: Receiver<usize>,
+) {
+    let mut last: Option<Arc<Preview>> = None;
+
+    loop {
+        let messages = tokio::select! {
+            // A new preview renders before it scrolls
+            biased;
+
+            received = socket.recv() => match received {
+                Some(Ok(AxumMessage::Text(message))) => {
+                    handle_message(&message, &config);
+                    continue;
+                }
+                Some(Ok(_)) => continue,
+                _ => return,
+            },
+            changed = preview_rx.changed() => match changed {
+                Ok(()) => {
+                    let preview = preview_rx.borrow_and_update().clone();
+                    let messages = preview
+                        .messages_since(last.as_deref())
+                        .iter()
+                        .map(protocol::encode)
+                        .collect();
+
+                    last = Some(preview);
+                    messages
+                }
+                Err(_) => break,
+            },
+            changed = line_rx.changed() => match changed {
+                Ok(()) => {
+                    let line = *line_rx.borrow_and_update();
+                    vec![protocol::encode(&ServerMessage::Scroll { line })]
+                }
+                Err(_) => break,
+            },
+        };
+
+        for message in messages {
+            // The tab was closed
+            if socket.send(AxumMessage::Text(message)).await.is_err() {
+                return;
+            }
+        }
     }
 
     let _ = socket.send(AxumMessage::Close(None)).await;
 }
 
+/// Body of a request from an editor, rejected unless it's JSON from the preview's origin.
+///
+/// Browsers preflight cross-origin JSON requests, which this server never answers, so other
+/// pages can't post plain text forms instead.
+fn editor_request<T: DeserializeOwned>(
+    headers: &HeaderMap,
+    addr: &SocketAddr,
+    body: &str,
+) -> Result<T, StatusCode> {
+    if !same_origin(headers, addr) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+        .unwrap_or(false);
+
+    if !json {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    serde_json::from_str(body).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// Renders the posted buffer, `{"text": "# Title"}`. The file on disk is then only used to
+/// resolve relative paths, until it's saved again.
+pub async fn buffer_handler(
+    headers: HeaderMap,
+    Extension(addr): Extension<SocketAddr>,
+    Extension(config): Extension<crate::server::Config>,
+    body: String,
+) -> StatusCode {
+    let BufferMessage { text } = match editor_request(&headers, &addr, &body) {
+        Ok(message) => message,
+        Err(status) => return status,
+    };
+
+    match config.changes.send(Change::Buffer(text)) {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+#[derive(Deserialize)]
+struct ScrollRequest {
+    line: usize,
+}
+
+/// Scrolls the previews to the block of a source line, `{"line": 42}`, lines start at 1.
+pub async fn scroll_handler(
+    headers: HeaderMap,
+    Extension(addr): Extension<SocketAddr>,
+    Extension(line_tx): Extension<Arc<Sender<usize>>>,
+    body: String,
+) -> StatusCode {
+    match editor_request::<ScrollRequest>(&headers, &addr, &body) {
+        Ok(ScrollRequest { line }) => {
+            line_tx.send_replace(line);
+            StatusCode::NO_CONTENT
+        }
+        Err(status) => status,
+    }
+}
+
 pub async fn serve_static_file(
+    Extension(addr): Extension<SocketAddr>,
     Extension(config): Extension<crate::server::Config>,
     req: Request<Body>,
 ) -> impl IntoResponse {
+    if !same_origin(req.headers(), &addr) {
+        return Err((StatusCode::FORBIDDEN, String::new()));
+    }
+
     let service = ServeDir::new(config.root_dir);
 
-    service
+    let mut response = service
         .oneshot(req)
         .await
-        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+
+    // Files next to the document can't run scripts on the origin of the preview
+    if config.render_options.safe.is_some() {
+        response.headers_mut().insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("sandbox"),
+        );
+    }
+
+    Ok::<_, (StatusCode, String)>(response)
 }
@@ -0,0 +1,292 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use colored::Colorize;
+use lol_html::{element, HtmlRewriter, Settings};
+use serde::Serialize;
+
+use crate::document::{Document, RenderOptions};
+use crate::{html, info, ioutil, transclude, urls};
+
+/// Markdown file of the site, paths are relative to the site root.
+struct Page {
+    output: PathBuf,
+    title: String,
+    document: Document,
+}
+
+/// Directory of the site, mirrors the source tree.
+#[derive(Default)]
+struct Section {
+    name: String,
+    pages: Vec<usize>,
+    sections: Vec<Section>,
+}
+
+#[derive(Serialize)]
+pub struct Link {
+    pub href: String,
+    pub title: String,
+}
+
+/// Site navigation for a single page.
+#[derive(Serialize)]
+pub struct Navigation {
+    pub sidebar: String,
+    pub prev: Option<Link>,
+    pub next: Option<Link>,
+}
+
+/// Every markdown file found in a directory, rendered as a linked set of pages.
+pub struct Site {
+    root: PathBuf,
+    pages: Vec<Page>,
+    tree: Section,
+}
+
+impl Site {
+    /// Collects markdown files under `root`, skipping hidden entries and the `exclude` directory.
+    pub fn scan(root: &Path, exclude: &Path, options: &RenderOptions) -> Result<Self> {
+        let mut site = Site {
+            root: root.to_path_buf(),
+            pages: Vec::new(),
+            tree: Section::default(),
+        };
+
+        let exclude = exclude.canonicalize().ok();
+        site.tree = site.scan_dir(Path::new(""), exclude.as_deref(), options)?;
+
+        Ok(site)
+    }
+
+    fn scan_dir(
+        &mut self,
+        dir: &Path,
+        exclude: Option<&Path>,
+        options: &RenderOptions,
+    ) -> Result<Section> {
+        let mut section = Section {
+            name: dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            ..Section::default()
+        };
+
+        let mut entries: Vec<PathBuf> = fs::read_dir(self.root.join(dir))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                !path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().starts_with('.'))
+                    .unwrap_or(true)
+            })
+            .collect();
+
+        // Index pages go first, then the rest alphabetically
+        entries.sort_by_key(|path| (!is_index(path), path.clone()));
+
+        let mut subdirs = Vec::new();
+
+        for path in entries {
+            let relative = dir.join(path.file_name().unwrap());
+
+            if path.is_dir() {
+                if exclude.is_some() && path.canonicalize().ok().as_deref() == exclude {
+                    continue;
+                }
+
+                subdirs.push(relative);
+            } else if path.extension().map(|ext| ext == "md").unwrap_or(false) {
+                let text = transclude::expand(
+                    &ioutil::read_path(&path)?,
+                    Some(&path),
+                    options.safe.is_some(),
+                )?
+                .text;
+                let mut document = Document::new(text, options.clone());
+                document.base_dir = urls::parent_dir(&path);
+                let title = document
+                    .title()
+                    .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().to_string());
+
+                section.pages.push(self.pages.len());
+                self.pages.push(Page {
+                    output: relative.with_extension("html"),
+                    title,
+                    document,
+                });
+            }
+        }
+
+        for subdir in subdirs {
+            let subsection = self.scan_dir(&subdir, exclude, options)?;
+
+            if !subsection.pages.is_empty() || !subsection.sections.is_empty() {
+                section.sections.push(subsection);
+            }
+        }
+
+        Ok(section)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Renders every page into `out_dir`, mirroring the source tree.
+    pub fn build(&mut self, out_dir: &Path) -> Result<()> {
+        for index in 0..self.pages.len() {
+            let navigation = self.navigation(index);
+
+            let page = &mut self.pages[index];
+            page.document.navigation = Some(navigation);
+            page.document.out_dir = Some(urls::parent_dir(&out_dir.join(&page.output)));
+
+            let html = String::from_utf8(page.document.render()?)?;
+            let html = rewrite_markdown_links(&html)?;
+
+            let output = out_dir.join(&page.output);
+            if let Some(parent) = output.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::write(&output, html)?;
+            info!("wrote {}", output.display().to_string().cyan());
+        }
+
+        Ok(())
+    }
+
+    fn navigation(&self, index: usize) -> Navigation {
+        let current = &self.pages[index];
+        let link = |page: &Page| Link {
+            href: relative_url(&current.output, &page.output),
+            title: page.title.clone(),
+        };
+
+        let order = self.order();
+        let position = order.iter().position(|&i| i == index).unwrap();
+
+        Navigation {
+            sidebar: self.render_section(&self.tree, index),
+            prev: position
+                .checked_sub(1)
+                .map(|position| link(&self.pages[order[position]])),
+            next: order.get(position + 1).map(|&next| link(&self.pages[next])),
+        }
+    }
+
+    /// Pages in the order they appear in the sidebar.
+    fn order(&self) -> Vec<usize> {
+        fn walk(section: &Section, order: &mut Vec<usize>) {
+            order.extend(&section.pages);
+
+            for subsection in &section.sections {
+                walk(subsection, order);
+            }
+        }
+
+        let mut order = Vec::new();
+        walk(&self.tree, &mut order);
+
+        order
+    }
+
+    fn render_section(&self, section: &Section, current: usize) -> String {
+        let mut html = String::from("<ul>\n");
+
+        for &index in &section.pages {
+            let page = &self.pages[index];
+            let href = relative_url(&self.pages[current].output, &page.output);
+
+            if index == current {
+                html.push_str(&format!(
+                    "<li><a href=\"{}\" aria-current=\"page\"><strong>{}</strong></a></li>\n",
+                    html::escape(&href),
+                    html::escape(&page.title)
+                ));
+            } else {
+                html.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    html::escape(&href),
+                    html::escape(&page.title)
+                ));
+            }
+        }
+
+        for subsection in &section.sections {
+            html.push_str(&format!(
+                "<li>{}\n{}</li>\n",
+                html::escape(&subsection.name),
+                self.render_section(subsection, current)
+            ));
+        }
+
+        html.push_str("</ul>\n");
+        html
+    }
+}
+
+fn is_index(path: &Path) -> bool {
+    path.file_stem()
+        .map(|stem| {
+            let stem = stem.to_string_lossy().to_lowercase();
+            stem == "index" || stem == "readme"
+        })
+        .unwrap_or(false)
+}
+
+/// Relative URL from one output file to another, both relative to the site root.
+fn relative_url(from: &Path, to: &Path) -> String {
+    urls::relative_url(from.parent().unwrap_or(Path::new("")), to)
+}
+
+/// Points relative links to other markdown files at their rendered pages.
+fn rewrite_markdown_links(html_page: &str) -> Result<String> {
+    let mut output = vec![];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!("a[href]", |el| {
+                let href = el.get_attribute("href").expect("href was required");
+
+                if let Some(rewritten) = markdown_link_to_html(&href) {
+                    el.set_attribute("href", &rewritten)?;
+                }
+
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
+
+fn markdown_link_to_html(href: &str) -> Option<String> {
+    // Absolute URLs, `mailto:` and the like
+    if href.contains("://")
+        || href.starts_with("//")
+        || href.split(['/', '#']).next()?.contains(':')
+    {
+        return None;
+    }
+
+    let split = href.find(['#', '?']).unwrap_or(href.len());
+    let (path, suffix) = href.split_at(split);
+
+    let stem = path.strip_suffix(".md")?;
+
+    Some(format!("{}.html{}", stem, suffix))
+}
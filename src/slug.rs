@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+
+/// Generates heading anchors the same way GitHub does.
+///
+/// Port of https://github.com/Flet/github-slugger
+#[derive(Default)]
+pub struct Slugger {
+    occurrences: HashMap<String, usize>,
+}
+
+impl Slugger {
+    /// Returns a unique slug for the given heading text, appending `-1`, `-2`, ... to duplicates.
+    pub fn slug(&mut self, value: &str) -> String {
+        let original = slugify(value);
+        let mut slug = original.clone();
+
+        while self.occurrences.contains_key(&slug) {
+            let count = self.occurrences.entry(original.clone()).or_insert(0);
+            *count += 1;
+            slug = format!("{}-{}", original, count);
+        }
+
+        self.occurrences.insert(slug.clone(), 0);
+
+        slug
+    }
+}
+
+pub fn slugify(value: &str) -> String {
+    value
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect()
+}
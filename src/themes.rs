@@ -7,10 +7,16 @@ use std::fs::File;
 use std::io::{self, prelude::*};
 use std::path::PathBuf;
 
+/// Bundled themes with a dark background.
+const DARK_THEMES: [&str; 2] = ["retro", "sakura-dark"];
+
 #[derive(serde::Deserialize, Clone)]
 pub struct Theme {
     pub name: String,
 
+    #[serde(default)]
+    pub dark: bool,
+
     path: Option<PathBuf>,
     inline: Option<String>,
 }
@@ -23,8 +29,7 @@ impl Theme {
             } else if self.path.is_some() {
                 self.resolve_path()?
             } else {
-                return Err(anyhow::Error::new(io::Error::new(
-                    std::io::ErrorKind::Other,
+                return Err(anyhow::Error::new(io::Error::other(
                     "theme source is not specified",
                 )));
             }
@@ -99,7 +104,7 @@ impl Default for Themes {
             .get_dir("themes")
             .expect("themes directory in vendor/ must be present")
             .entries()
-            .into_iter()
+            .iter()
             .filter_map(|entry| entry.as_file())
             .filter_map(|file| {
                 let path = file.path();
@@ -118,6 +123,7 @@ impl Default for Themes {
                 }
             })
             .map(|(name, contents)| Theme {
+                dark: DARK_THEMES.contains(&name.as_str()),
                 name,
                 inline: Some(contents.to_string()),
                 path: None,
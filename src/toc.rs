@@ -0,0 +1,139 @@
+use markdown::mdast::Node;
+
+use crate::html;
+use crate::slug::Slugger;
+
+const REGION_START: &str = "<!--toc:start-->";
+const REGION_END: &str = "<!--toc:end-->";
+/// End of the `[TOC]` paragraph, its start tag may have attributes.
+const PLACEHOLDER: &str = "[TOC]</p>";
+
+pub struct Heading {
+    pub depth: u8,
+    pub text: String,
+    pub slug: String,
+    /// Offset of the heading in the source.
+    pub offset: Option<usize>,
+}
+
+/// Collects all headings of the document in order, assigning each one a unique slug.
+pub fn headings(root: &Node) -> Vec<Heading> {
+    let mut slugger = Slugger::default();
+    let mut headings = Vec::new();
+
+    collect_headings(root, &mut slugger, &mut headings);
+
+    headings
+}
+
+fn collect_headings(node: &Node, slugger: &mut Slugger, headings: &mut Vec<Heading>) {
+    if let Node::Heading(heading) = node {
+        let text = node.to_string();
+
+        headings.push(Heading {
+            depth: heading.depth,
+            slug: slugger.slug(&text),
+            text,
+            offset: node.position().map(|position| position.start.offset),
+        });
+
+        return;
+    }
+
+    if let Some(children) = node.children() {
+        for child in children {
+            collect_headings(child, slugger, headings);
+        }
+    }
+}
+
+/// Renders headings as nested lists of links.
+pub fn render(headings: &[Heading]) -> String {
+    let mut html = String::new();
+    let mut depths: Vec<u8> = Vec::new();
+
+    for heading in headings {
+        match depths.last().copied() {
+            None => {
+                html.push_str("<ul>\n<li>");
+                depths.push(heading.depth);
+            }
+            Some(top) if heading.depth > top => {
+                html.push_str("\n<ul>\n<li>");
+                depths.push(heading.depth);
+            }
+            Some(_) => {
+                while depths.len() > 1 && heading.depth <= depths[depths.len() - 2] {
+                    html.push_str("</li>\n</ul>\n");
+                    depths.pop();
+                }
+
+                html.push_str("</li>\n<li>");
+                *depths.last_mut().unwrap() = heading.depth;
+            }
+        }
+
+        html.push_str(&format!(
+            "<a href=\"#{}\">{}</a>",
+            html::escape(&heading.slug),
+            html::escape(&heading.text)
+        ));
+    }
+
+    for _ in depths {
+        html.push_str("</li>\n</ul>\n");
+    }
+
+    html
+}
+
+/// Replaces the `<!--toc:start-->`/`<!--toc:end-->` region or the `[TOC]` placeholder with the table of contents.
+pub fn fill(html: String, toc: &str) -> String {
+    if let (Some(start), Some(end)) = (html.find(REGION_START), html.find(REGION_END)) {
+        if start < end {
+            return format!(
+                "{}\n{}{}",
+                &html[..start + REGION_START.len()],
+                toc,
+                &html[end..]
+            );
+        }
+    }
+
+    let mut filled = String::with_capacity(html.len());
+    let mut rest = html.as_str();
+
+    while let Some(end) = rest.find(PLACEHOLDER) {
+        let placeholder = paragraph_start(&rest[..end]);
+        let after = end + PLACEHOLDER.len();
+
+        match placeholder {
+            // Attributes of the paragraph, like its source line, move to the list
+            Some((start, attributes)) => {
+                filled.push_str(&rest[..start]);
+                match toc.strip_prefix("<ul") {
+                    Some(list) => filled.push_str(&format!("<ul{}{}", attributes, list)),
+                    None => filled.push_str(toc),
+                }
+            }
+            None => filled.push_str(&rest[..after]),
+        }
+
+        rest = &rest[after..];
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+/// Offset and attributes of the `<p>` start tag the text ends with.
+fn paragraph_start(html: &str) -> Option<(usize, &str)> {
+    let tag = html.strip_suffix('>')?;
+    let start = tag.rfind("<p")?;
+    let attributes = &tag[start + 2..];
+
+    let valid =
+        attributes.is_empty() || (attributes.starts_with(' ') && !attributes.contains(['<', '>']));
+
+    valid.then_some((start, attributes))
+}
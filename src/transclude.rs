@@ -0,0 +1,410 @@
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use markdown::mdast::Node;
+
+use crate::{slug, urls};
+
+/// Directive syntaxes, `{{#include file.md}}` as in mdBook and `<!-- include: file.md -->`.
+const DIRECTIVES: [(&str, &str); 2] = [("{{#include ", "}}"), ("<!-- include:", "-->")];
+
+/// Escaped directives are kept literally, without the backslash.
+const ESCAPE: char = '\\';
+
+/// Part of the included file.
+enum Selection {
+    All,
+    /// 1-based inclusive line range, `file.md:10:20`, `file.md:10:` or `file.md::20`
+    Lines(Option<usize>, Option<usize>),
+    /// Heading with the given slug and everything up to the next heading of the same or higher level, `file.md#usage`
+    Section(String),
+}
+
+/// Chain of files being included, used to detect cycles and to report errors.
+struct Chain {
+    files: Vec<PathBuf>,
+    /// Untrusted documents can't read other files.
+    safe: bool,
+    /// Every file included so far.
+    included: Vec<PathBuf>,
+}
+
+impl Chain {
+    fn display(&self) -> String {
+        self.files
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" → ")
+    }
+}
+
+/// Text with its include directives resolved.
+pub struct Expanded {
+    pub text: String,
+    /// Files included directly or by other included files, the watcher follows them too.
+    pub files: Vec<PathBuf>,
+}
+
+/// Resolves include directives recursively, relative to the file that contains them.
+///
+/// Text without a source file, e.g. from stdin, resolves includes against the current directory.
+/// In safe mode any directive outside of code is an error.
+pub fn expand(text: &str, source: Option<&Path>, safe: bool) -> Result<Expanded> {
+    let mut chain = Chain {
+        files: Vec::new(),
+        safe,
+        included: Vec::new(),
+    };
+    let base_dir = match source {
+        Some(source) => {
+            chain.files.push(source.to_path_buf());
+            source.parent().unwrap_or(Path::new("")).to_path_buf()
+        }
+        None => PathBuf::new(),
+    };
+
+    let text = expand_in(text, &base_dir, &mut chain)?;
+
+    Ok(Expanded {
+        text,
+        files: chain.included,
+    })
+}
+
+fn expand_in(text: &str, base_dir: &Path, chain: &mut Chain) -> Result<String> {
+    let code = code_ranges(text);
+
+    let mut result = String::with_capacity(text.len());
+    let mut offset = 0;
+
+    while let Some((start, open, close)) = next_directive(&text[offset..]) {
+        let start = offset + start;
+
+        // Code spans and blocks show directives, like the examples of a README
+        if let Some(range) = code.iter().find(|range| range.contains(&start)) {
+            result.push_str(&text[offset..range.end]);
+            offset = range.end;
+            continue;
+        }
+
+        let escaped = text[offset..start].ends_with(ESCAPE);
+
+        let args_start = start + open.len();
+        let end = match text[args_start..].find(close) {
+            Some(end) => args_start + end,
+            None => break,
+        };
+
+        if escaped {
+            result.push_str(&text[offset..start - ESCAPE.len_utf8()]);
+            result.push_str(&text[start..end + close.len()]);
+        } else {
+            result.push_str(&text[offset..start]);
+            result.push_str(&include(text[args_start..end].trim(), base_dir, chain)?);
+        }
+
+        offset = end + close.len();
+    }
+
+    result.push_str(&text[offset..]);
+    Ok(result)
+}
+
+fn parse_options() -> markdown::ParseOptions {
+    markdown::ParseOptions {
+        constructs: markdown::Constructs {
+            frontmatter: true,
+            ..markdown::Constructs::gfm()
+        },
+        ..markdown::ParseOptions::gfm()
+    }
+}
+
+/// Byte ranges of the code spans and code blocks of the text.
+fn code_ranges(text: &str) -> Vec<Range<usize>> {
+    fn visit(node: &Node, ranges: &mut Vec<Range<usize>>) {
+        match node {
+            Node::Code(_) | Node::InlineCode(_) => {
+                if let Some(position) = node.position() {
+                    ranges.push(position.start.offset..position.end.offset);
+                }
+            }
+            _ => {
+                for child in node.children().into_iter().flatten() {
+                    visit(child, ranges);
+                }
+            }
+        }
+    }
+
+    let mut ranges = Vec::new();
+    if let Ok(root) = markdown::to_mdast(text, &parse_options()) {
+        visit(&root, &mut ranges);
+    }
+
+    ranges
+}
+
+fn next_directive(text: &str) -> Option<(usize, &'static str, &'static str)> {
+    DIRECTIVES
+        .iter()
+        .filter_map(|&(open, close)| text.find(open).map(|start| (start, open, close)))
+        .min_by_key(|&(start, _, _)| start)
+}
+
+fn include(args: &str, base_dir: &Path, chain: &mut Chain) -> Result<String> {
+    if chain.safe {
+        bail!("can't include {}: includes are disabled in safe mode", args);
+    }
+
+    let (path, selection) = parse_args(args)?;
+    let path = base_dir.join(path);
+
+    let canonical = path.canonicalize().map_err(|e| {
+        anyhow!(
+            "can't include {}: {} (included from {})",
+            path.display(),
+            e,
+            chain.display()
+        )
+    })?;
+
+    if chain
+        .files
+        .iter()
+        .any(|file| file.canonicalize().ok().as_ref() == Some(&canonical))
+    {
+        chain.files.push(path);
+        bail!("include cycle: {}", chain.display());
+    }
+
+    let text = fs::read_to_string(&path).with_context(|| {
+        format!(
+            "can't include {} (included from {})",
+            path.display(),
+            chain.display()
+        )
+    })?;
+
+    let selected = select(&text, &selection).ok_or_else(|| {
+        anyhow!(
+            "{} has no {} (included from {})",
+            path.display(),
+            match &selection {
+                Selection::Section(name) => format!("section #{}", name),
+                _ => "such lines".to_string(),
+            },
+            chain.display()
+        )
+    })?;
+
+    chain.files.push(path.clone());
+    if !chain.included.contains(&path) {
+        chain.included.push(path.clone());
+    }
+
+    let fragment_dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let expanded = expand_in(&selected, &fragment_dir, chain)?;
+
+    chain.files.pop();
+
+    rebase(&expanded, &fragment_dir, base_dir)
+}
+
+/// Rewrites the relative links, images and HTML references of a fragment, written from its own
+/// directory, so that they point to the same files from the including one.
+fn rebase(text: &str, fragment_dir: &Path, base_dir: &Path) -> Result<String> {
+    // Files in the current directory have an empty parent
+    let absolute = |dir: &Path| std::path::absolute(Path::new(".").join(dir));
+    let fragment_dir = urls::normalize(&absolute(fragment_dir)?);
+    let base_dir = urls::normalize(&absolute(base_dir)?);
+
+    if fragment_dir == base_dir {
+        return Ok(text.to_string());
+    }
+
+    let root = match markdown::to_mdast(text, &parse_options()) {
+        Ok(root) => root,
+        Err(_) => return Ok(text.to_string()),
+    };
+
+    let mut edits = Vec::new();
+    rebase_node(&root, text, &fragment_dir, &base_dir, &mut edits)?;
+    edits.sort_by_key(|(range, _): &(Range<usize>, String)| range.start);
+
+    let mut rebased = text.to_string();
+    for (range, replacement) in edits.into_iter().rev() {
+        rebased.replace_range(range, &replacement);
+    }
+
+    Ok(rebased)
+}
+
+fn rebase_node(
+    node: &Node,
+    text: &str,
+    fragment_dir: &Path,
+    base_dir: &Path,
+    edits: &mut Vec<(Range<usize>, String)>,
+) -> Result<()> {
+    let range = match node.position() {
+        Some(position) => position.start.offset..position.end.offset,
+        None => return Ok(()),
+    };
+
+    let destination = match node {
+        Node::Code(_) | Node::InlineCode(_) => return Ok(()),
+        Node::Link(_) | Node::Image(_) => inline_destination(&text[range.clone()]),
+        Node::Definition(_) => definition_destination(&text[range.clone()]),
+        // Raw HTML spanning container markers like `> ` isn't a slice of the source
+        Node::Html(html) if text[range.clone()] == html.value => {
+            let rebased = urls::rebase(&html.value, fragment_dir, base_dir, false)?;
+            if rebased != html.value {
+                edits.push((range, rebased));
+            }
+            return Ok(());
+        }
+        _ => None,
+    };
+
+    if let Some(destination) = destination {
+        let destination = range.start + destination.start..range.start + destination.end;
+        let url = &text[destination.clone()];
+
+        if urls::is_relative_local(url) {
+            let (_, suffix) = urls::split_suffix(url);
+            let target = urls::normalize(&urls::resolve(fragment_dir, url));
+            edits.push((
+                destination,
+                format!("{}{}", urls::relative_url(base_dir, &target), suffix),
+            ));
+        }
+    }
+
+    for child in node.children().into_iter().flatten() {
+        rebase_node(child, text, fragment_dir, base_dir, edits)?;
+    }
+
+    Ok(())
+}
+
+/// Destination of `[text](url "title")` or `![alt](<url>)`, relative to the start of the link.
+///
+/// References like `[text][label]` have none, their definition is rebased instead.
+fn inline_destination(link: &str) -> Option<Range<usize>> {
+    let inner = link.strip_suffix(')')?;
+    let start = inner.rfind("](")? + 2;
+
+    destination(inner, start)
+}
+
+/// Destination of `[label]: url "title"`, relative to the start of the definition.
+fn definition_destination(definition: &str) -> Option<Range<usize>> {
+    let start = definition.find("]:")? + 2;
+
+    destination(definition, start)
+}
+
+/// URL starting at `start` after optional whitespace, until whitespace or in `<...>`.
+fn destination(text: &str, start: usize) -> Option<Range<usize>> {
+    let start = start + (text[start..].len() - text[start..].trim_start().len());
+
+    if text[start..].starts_with('<') {
+        let end = text[start..].find('>')?;
+        return Some(start + 1..start + end);
+    }
+
+    let end = text[start..]
+        .find(char::is_whitespace)
+        .map(|end| start + end)
+        .unwrap_or(text.len());
+
+    (end > start).then_some(start..end)
+}
+
+fn parse_args(args: &str) -> Result<(&str, Selection)> {
+    if args.is_empty() {
+        bail!("include directive has no path");
+    }
+
+    if let Some((path, section)) = args.split_once('#') {
+        return Ok((path, Selection::Section(section.to_string())));
+    }
+
+    let mut parts = args.splitn(3, ':');
+    let path = parts.next().unwrap();
+
+    let mut line = || -> Result<Option<usize>> {
+        match parts.next().map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(line) => line
+                .parse()
+                .map(Some)
+                .map_err(|_| anyhow!("invalid line number {} in include {}", line, args)),
+        }
+    };
+
+    let start = line()?;
+    let end = line()?;
+
+    if start.is_none() && end.is_none() {
+        Ok((path, Selection::All))
+    } else {
+        Ok((path, Selection::Lines(start, end)))
+    }
+}
+
+fn select(text: &str, selection: &Selection) -> Option<String> {
+    match selection {
+        Selection::All => Some(text.to_string()),
+        Selection::Lines(start, end) => {
+            let start = start.unwrap_or(1).max(1);
+            let lines: Vec<&str> = text
+                .lines()
+                .skip(start - 1)
+                .take(
+                    end.map(|end| (end + 1).saturating_sub(start))
+                        .unwrap_or(usize::MAX),
+                )
+                .collect();
+
+            if lines.is_empty() {
+                return None;
+            }
+
+            Some(lines.join("\n"))
+        }
+        Selection::Section(name) => select_section(text, name),
+    }
+}
+
+fn select_section(text: &str, name: &str) -> Option<String> {
+    let root = markdown::to_mdast(text, &parse_options()).ok()?;
+    let children = root.children()?;
+
+    let (index, depth) = children
+        .iter()
+        .enumerate()
+        .find_map(|(index, child)| match child {
+            Node::Heading(heading) if slug::slugify(&child.to_string()) == name => {
+                Some((index, heading.depth))
+            }
+            _ => None,
+        })?;
+
+    let start = children[index].position()?.start.offset;
+    let end = children[index + 1..]
+        .iter()
+        .find_map(|child| match child {
+            Node::Heading(heading) if heading.depth <= depth => {
+                child.position().map(|position| position.start.offset)
+            }
+            _ => None,
+        })
+        .unwrap_or(text.len());
+
+    Some(text[start..end].trim_end().to_string())
+}
@@ -0,0 +1,242 @@
+use std::path::{Component, Path, PathBuf};
+
+use lol_html::{element, HtmlRewriter, Settings};
+
+/// Elements and attributes that reference other files.
+pub const ASSET_ATTRIBUTES: [(&str, &str); 13] = [
+    ("a[href]", "href"),
+    ("img[src]", "src"),
+    ("img[srcset]", "srcset"),
+    ("source[srcset]", "srcset"),
+    ("source[src]", "src"),
+    ("video[src]", "src"),
+    ("video[poster]", "poster"),
+    ("audio[src]", "src"),
+    ("track[src]", "src"),
+    ("link[href]", "href"),
+    ("object[data]", "data"),
+    ("iframe[src]", "src"),
+    ("embed[src]", "src"),
+];
+
+/// Whether the URL points to a file relative to the document, not to a page anchor, a remote or absolute resource.
+pub fn is_relative_local(url: &str) -> bool {
+    let url = url.trim();
+
+    if url.is_empty() || url.starts_with(['#', '/', '?']) {
+        return false;
+    }
+
+    // Schemes such as `https:`, `data:` or `mailto:`
+    match url.find(':') {
+        Some(colon) => url[..colon].contains(['/', '?', '#']),
+        None => true,
+    }
+}
+
+/// Splits the path of a URL from its `?query` and `#fragment`.
+pub fn split_suffix(url: &str) -> (&str, &str) {
+    url.split_at(url.find(['?', '#']).unwrap_or(url.len()))
+}
+
+/// Directory containing the file, `.` for bare file names.
+pub fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Removes `.` and `..` components without touching the filesystem.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => (),
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// Candidates of a `srcset`, URL and descriptor.
+///
+/// URLs end at whitespace rather than at a comma, data URIs contain commas.
+pub fn srcset_candidates(srcset: &str) -> Vec<(&str, &str)> {
+    let mut candidates = Vec::new();
+    let mut rest = srcset;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        if rest.is_empty() {
+            break;
+        }
+
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let url = &rest[..end];
+        rest = &rest[end..];
+
+        // A trailing comma ends a candidate without a descriptor
+        if let Some(url) = url.strip_suffix(',') {
+            candidates.push((url.trim_end_matches(','), ""));
+            continue;
+        }
+
+        let end = rest.find(',').unwrap_or(rest.len());
+        candidates.push((url, rest[..end].trim()));
+        rest = &rest[end..];
+    }
+
+    candidates
+}
+
+/// Filesystem path of a relative URL, resolved against the document directory.
+pub fn resolve(base_dir: &Path, url: &str) -> PathBuf {
+    let (path, _) = split_suffix(url);
+
+    base_dir.join(percent_decode(path))
+}
+
+/// Relative URL from a directory to a file, both relative to the same root.
+pub fn relative_url(from_dir: &Path, to: &Path) -> String {
+    let from_dir: Vec<Component> = from_dir
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    let to: Vec<Component> = to
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+
+    let common = from_dir
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts: Vec<String> = vec!["..".into(); from_dir.len() - common];
+    parts.extend(
+        to[common..]
+            .iter()
+            .map(|component| percent_encode(&component.as_os_str().to_string_lossy())),
+    );
+
+    parts.join("/")
+}
+
+/// Rewrites relative references of the document so that they keep working from `out_dir`.
+///
+/// Links to other markdown files are kept when `keep_markdown` is set, a site rewrites them to its pages.
+pub fn rebase(
+    html_page: &str,
+    base_dir: &Path,
+    out_dir: &Path,
+    keep_markdown: bool,
+) -> anyhow::Result<String> {
+    let base_dir = normalize(&std::path::absolute(base_dir)?);
+    let out_dir = normalize(&std::path::absolute(out_dir)?);
+
+    if base_dir == out_dir {
+        return Ok(html_page.to_string());
+    }
+
+    let mut output = vec![];
+
+    let handlers = ASSET_ATTRIBUTES
+        .iter()
+        .map(|&(selector, attribute)| {
+            let base_dir = &base_dir;
+            let out_dir = &out_dir;
+
+            element!(selector, move |el| {
+                let value = el.get_attribute(attribute).expect("attribute was required");
+
+                let rebase_url = |url: &str| {
+                    let (path, suffix) = split_suffix(url);
+
+                    if !is_relative_local(url) || (keep_markdown && path.ends_with(".md")) {
+                        return url.to_string();
+                    }
+
+                    let target = resolve(base_dir, url);
+                    format!("{}{}", relative_url(out_dir, &target), suffix)
+                };
+
+                if attribute == "srcset" {
+                    let candidates: Vec<String> = srcset_candidates(&value)
+                        .into_iter()
+                        .map(|(url, descriptor)| {
+                            format!("{} {}", rebase_url(url), descriptor)
+                                .trim_end()
+                                .to_string()
+                        })
+                        .collect();
+
+                    el.set_attribute(attribute, &candidates.join(", "))?;
+                } else {
+                    el.set_attribute(attribute, &rebase_url(&value))?;
+                }
+
+                Ok(())
+            })
+        })
+        .collect();
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: handlers,
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+
+    rewriter.write(html_page.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8(output)?)
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    encoded
+}
@@ -1,50 +1,136 @@
 use anyhow::Result;
 use colored::Colorize;
 use notify::Watcher;
+use serde::Deserialize;
 
-use crate::{document, error, info, ioutil};
+use crate::{document, error, info, ioutil, transclude, urls, warn};
 use std::{
-    io, net,
+    io::{self, BufRead},
+    net,
     path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread,
 };
 
-fn recompile(path: &PathBuf, options: &document::RenderOptions) -> io::Result<document::Document> {
-    match ioutil::read_path(path) {
-        Ok(contents) => Ok(document::Document {
-            text: contents,
-            options: options.clone(),
-        }),
-        Err(e) => Err(e),
+/// What makes the document render again.
+pub enum Change {
+    File(notify::Result<notify::Event>),
+    /// Unsaved contents of the file sent by an editor.
+    Buffer(String),
+}
+
+/// Buffer posted to `/buffer`, or a line of the newline-delimited JSON read with `--stdin-buffer`.
+#[derive(Deserialize)]
+pub struct BufferMessage {
+    pub text: String,
+}
+
+fn recompile(
+    path: &PathBuf,
+    output: Option<&Path>,
+    options: &document::RenderOptions,
+) -> Result<document::Document> {
+    compile(ioutil::read_path(path)?, path, output, options)
+}
+
+/// Relative paths of the text are still resolved against the file.
+fn compile(
+    text: String,
+    path: &Path,
+    output: Option<&Path>,
+    options: &document::RenderOptions,
+) -> Result<document::Document> {
+    let expanded = transclude::expand(&text, Some(path), options.safe.is_some())?;
+
+    let mut document = document::Document::new(expanded.text, options.clone());
+    document.base_dir = urls::parent_dir(path);
+    document.out_dir = output.map(urls::parent_dir);
+    document.includes = expanded.files;
+
+    Ok(document)
+}
+
+/// Follows the files the document includes, a failed compilation keeps the previous ones.
+fn watch_includes(
+    watcher: &mut notify::RecommendedWatcher,
+    watched: &mut Vec<PathBuf>,
+    compiled: &Result<document::Document>,
+) {
+    let includes = match compiled {
+        Ok(document) => &document.includes,
+        Err(_) => return,
+    };
+
+    for file in watched.iter().filter(|file| !includes.contains(file)) {
+        let _ = watcher.unwatch(file);
     }
+
+    for file in includes.iter().filter(|file| !watched.contains(file)) {
+        if let Err(e) = watcher.watch(file, notify::RecursiveMode::NonRecursive) {
+            warn!("can't watch {}: {}", file.display(), e);
+        }
+    }
+
+    *watched = includes.clone();
 }
 
 macro_rules! watch {
-    ($path: ident, $options:ident, $on_update:ident$(.$field:ident)*$( $arg:ident)*) => {{
+    ($path: ident, $output:expr, $options:ident, $changes:expr, $on_update:ident$(.$field:ident)*$( $arg:ident)*) => {{
         info!("waiting for changes on {}", $path.display().to_string().cyan());
 
-        let (tx, rx) = std::sync::mpsc::channel();
-        let mut watcher = notify::RecommendedWatcher::new(tx, notify::Config::default())?;
+        let (tx, rx) = $changes;
+        let mut watcher = notify::RecommendedWatcher::new(
+            move |res| {
+                let _ = tx.send(Change::File(res));
+            },
+            notify::Config::default(),
+        )?;
 
         watcher.watch($path.as_path(), notify::RecursiveMode::NonRecursive)?;
 
-        if let Ok(compiled) = recompile($path, $options) {
-            $on_update$(.$field)*($($arg,)* &compiled).await;
+        let mut included = Vec::new();
+
+        let compiled = recompile($path, $output, $options);
+        watch_includes(&mut watcher, &mut included, &compiled);
+        if let Err(e) = &compiled {
+            error!("compilation failed: {}", e);
         }
+        $on_update$(.$field)*($($arg,)* &compiled).await;
+
+        // The latest change wins, a save renders the file again after buffers
+        let mut buffered = false;
 
-        for res in rx {
-            match res {
-                Ok(event) => {
-                    if event.kind.is_modify() {
-                        match recompile($path, $options) {
-                            Ok(compiled) => {
-                                $on_update$(.$field)*($($arg,)* &compiled).await;
-                                info!("updated")
-                            },
-                            Err(e) => error!("compilation failed: {}", e)
-                        }
+        for change in rx {
+            let compiled = match change {
+                Change::Buffer(text) => {
+                    if !buffered {
+                        info!("following the editor buffer until the file is saved");
+                        buffered = true;
                     }
+
+                    compile(text, $path, $output, $options)
+                }
+                Change::File(Ok(event)) if event.kind.is_modify() => {
+                    if buffered {
+                        info!("following the file again");
+                        buffered = false;
+                    }
+
+                    recompile($path, $output, $options)
+                }
+                Change::File(Ok(_)) => continue,
+                Change::File(Err(e)) => {
+                    error!("{}", e.to_string());
+                    continue;
                 }
-                Err(e) => error!("{}", e.to_string()),
+            };
+
+            watch_includes(&mut watcher, &mut included, &compiled);
+            $on_update$(.$field)*($($arg,)* &compiled).await;
+
+            match compiled {
+                Ok(_) => info!("updated"),
+                Err(e) => error!("compilation failed: {}", e),
             }
         }
 
@@ -52,16 +138,48 @@ macro_rules! watch {
     }};
 }
 
+/// Sends the buffers of newline-delimited JSON on stdin, like `{"text": "# Title"}`.
+fn read_stdin_buffers(changes: Sender<Change>) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => line,
+                Err(e) => {
+                    error!("can't read stdin: {}", e);
+                    return;
+                }
+            };
+
+            match serde_json::from_str::<BufferMessage>(&line) {
+                Ok(message) => {
+                    if changes.send(Change::Buffer(message.text)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => warn!("invalid buffer on stdin: {}", e),
+            }
+        }
+    });
+}
+
 pub async fn watch_live(
     path: &PathBuf,
     options: &document::RenderOptions,
     port: u16,
+    editor: Option<String>,
+    stdin_buffer: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let addr = net::SocketAddr::V4(net::SocketAddrV4::new(
         net::Ipv4Addr::new(127, 0, 0, 1),
         port,
     ));
 
+    let changes = mpsc::channel();
+    if stdin_buffer {
+        read_stdin_buffers(changes.0.clone());
+    }
+
     let config = crate::server::Config {
         root_dir: path
             .clone()
@@ -69,11 +187,19 @@ pub async fn watch_live(
             .unwrap_or(Path::new("."))
             .to_path_buf(),
         render_options: options.clone(),
+        source: path.clone(),
+        editor,
+        changes: changes.0.clone(),
     };
 
-    let server = crate::server::Server::bind(&addr, config);
+    let server = crate::server::Server::bind(&addr, config)?;
+
+    info!("Opening in browser");
+    if let Err(e) = open::that(format!("http://{}", server.addr)) {
+        warn!("Failed to open the page: {}", e);
+    }
 
-    watch!(path, options, server.send)
+    watch!(path, None, options, changes, server.send)
 }
 
 pub async fn watch_file(
@@ -81,10 +207,22 @@ pub async fn watch_file(
     output: &PathBuf,
     options: &document::RenderOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    watch!(path, options, write_to_file output)
+    watch!(
+        path,
+        Some(output.as_path()),
+        options,
+        mpsc::channel(),
+        write_to_file output
+    )
 }
 
-async fn write_to_file(path: &PathBuf, document: &document::Document) {
+async fn write_to_file(path: &PathBuf, document: &Result<document::Document>) {
+    // Failures are already reported
+    let document = match document {
+        Ok(document) => document,
+        Err(_) => return,
+    };
+
     match document.render() {
         Ok(rendered) => {
             if let Err(e) = std::fs::write(path, rendered) {